@@ -0,0 +1,112 @@
+use crate::ollama_api::OllamaRequestMessage;
+use chrono::Utc;
+use serde::Serialize;
+use std::io;
+use std::path::Path;
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::error;
+
+// records waiting to be written, after which logging waits for the writer
+const QUEUE_SIZE: usize = 64;
+
+/// Appends a JSONL record of every generation to a file, in the background
+pub struct AuditLog {
+    sender: AuditSender,
+    writer: JoinHandle<()>,
+}
+
+/// Queues records for the writer, cloned into the generation tasks
+#[derive(Clone)]
+pub struct AuditSender {
+    sender: mpsc::Sender<AuditRecord>,
+    redact_images: bool,
+}
+
+#[derive(Serialize)]
+struct AuditRecord {
+    time: String,
+    group: String,
+    messages: Vec<OllamaRequestMessage>,
+    response: String,
+}
+
+impl AuditLog {
+    pub async fn open(path: &Path, redact_images: bool) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+
+        let (sender, mut receiver) = mpsc::channel::<AuditRecord>(QUEUE_SIZE);
+
+        // a single writer, so records never interleave
+        let writer = tokio::spawn(async move {
+            while let Some(record) = receiver.recv().await {
+                let mut line = match serde_json::to_string(&record) {
+                    Ok(line) => line,
+                    Err(e) => {
+                        error!("Failed to serialize audit record. {e}");
+                        continue;
+                    }
+                };
+                line.push('\n');
+
+                if let Err(e) = file.write_all(line.as_bytes()).await {
+                    error!("Failed to write audit record. {e}");
+                }
+            }
+
+            if let Err(e) = file.flush().await {
+                error!("Failed to write audit records. {e}");
+            }
+        });
+
+        Ok(Self {
+            sender: AuditSender {
+                sender,
+                redact_images,
+            },
+            writer,
+        })
+    }
+    pub fn sender(&self) -> AuditSender {
+        self.sender.clone()
+    }
+    /// Waits for the queued records to be written. The senders given out must be gone by then,
+    /// or this waits for them too
+    pub async fn close(self) {
+        drop(self.sender);
+        if let Err(e) = self.writer.await {
+            error!("Audit log writer failed. {e}");
+        }
+    }
+}
+
+impl AuditSender {
+    pub async fn log(
+        &self,
+        group: String,
+        mut messages: Vec<OllamaRequestMessage>,
+        response: String,
+    ) {
+        if self.redact_images {
+            for image in messages.iter_mut().flat_map(|msg| &mut msg.images) {
+                *image = format!("<{} bytes of base64>", image.len());
+            }
+        }
+
+        let record = AuditRecord {
+            time: Utc::now().to_rfc3339(),
+            group,
+            messages,
+            response,
+        };
+
+        // the writer only stops once every sender is gone
+        let _ = self.sender.send(record).await;
+    }
+}
@@ -0,0 +1,95 @@
+use crate::config::ChatTemplate;
+use crate::ollama_api::OllamaRequestMessage;
+
+/// Renders the conversation into a single raw prompt, ending with an open assistant turn
+pub fn render(template: ChatTemplate, messages: &[OllamaRequestMessage]) -> String {
+    let mut prompt = String::new();
+
+    match template {
+        ChatTemplate::Chatml => {
+            for msg in messages {
+                prompt += &format!("<|im_start|>{}\n{}<|im_end|>\n", msg.role, msg.content);
+            }
+            prompt += "<|im_start|>assistant\n";
+        }
+        ChatTemplate::Llama3 => {
+            prompt += "<|begin_of_text|>";
+            for msg in messages {
+                prompt += &format!(
+                    "<|start_header_id|>{}<|end_header_id|>\n\n{}<|eot_id|>",
+                    msg.role, msg.content
+                );
+            }
+            prompt += "<|start_header_id|>assistant<|end_header_id|>\n\n";
+        }
+        ChatTemplate::Gemma => {
+            // gemma has no system role, so system messages become user turns
+            for msg in messages {
+                let role = match msg.role.as_str() {
+                    "assistant" => "model",
+                    _ => "user",
+                };
+                prompt += &format!("<start_of_turn>{role}\n{}<end_of_turn>\n", msg.content);
+            }
+            prompt += "<start_of_turn>model\n";
+        }
+    }
+
+    prompt
+}
+
+/// Flattens the conversation into a plain transcript for models without a chat template,
+/// ending with the assistant's name so that the model continues as it
+pub fn flatten(messages: &[OllamaRequestMessage], assistant_name: &str) -> String {
+    let mut prompt = String::new();
+
+    for msg in messages {
+        match msg.role.as_str() {
+            "assistant" => prompt += &format!("{assistant_name}: {}\n", msg.content),
+            // system prompts are their own paragraphs, user messages are already prefixed
+            "system" => prompt += &format!("{}\n\n", msg.content),
+            _ => prompt += &format!("{}\n", msg.content),
+        }
+    }
+    prompt += &format!("{assistant_name}:");
+
+    prompt
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: &str, content: &str) -> OllamaRequestMessage {
+        OllamaRequestMessage {
+            role: role.to_string(),
+            content: content.to_string(),
+            images: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn renders_chatml() {
+        let messages = [message("system", "be nice"), message("user", "hi")];
+
+        assert_eq!(
+            render(ChatTemplate::Chatml, &messages),
+            "<|im_start|>system\nbe nice<|im_end|>\n<|im_start|>user\nhi<|im_end|>\n<|im_start|>assistant\n"
+        );
+    }
+
+    #[test]
+    fn flattens_to_a_transcript() {
+        let messages = [
+            message("system", "be nice"),
+            message("user", "tom: hi bot"),
+            message("assistant", "hello"),
+            message("user", "tom: how are you?"),
+        ];
+
+        assert_eq!(
+            flatten(&messages, "bot"),
+            "be nice\n\ntom: hi bot\nbot: hello\ntom: how are you?\nbot:"
+        );
+    }
+}
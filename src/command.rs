@@ -0,0 +1,227 @@
+/// What a command does, handle_message has a branch for each
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandKind {
+    Help,
+    Memories,
+    AddMemory,
+    RemoveMemory,
+    Stats,
+    Forget,
+    Activity,
+    LastError,
+    ClearError,
+    Transcript,
+    Consolidate,
+    Relearn,
+    SetPrompt,
+    ResetPrompt,
+    Model,
+    WhoAmI,
+    SaveMemories,
+    Rooms,
+}
+
+pub struct CommandSpec {
+    pub kind: CommandKind,
+    // the first one is shown in /help
+    pub names: &'static [&'static str],
+    pub usage: &'static str,
+    pub description: &'static str,
+    // checked before the command is handled at all
+    pub admin: bool,
+}
+
+impl CommandSpec {
+    /// The line of this command in /help
+    pub fn help_line(&self) -> String {
+        let names = self
+            .names
+            .iter()
+            .map(|name| format!("/{name}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let usage = if self.usage.is_empty() {
+            names
+        } else {
+            format!("{names} {}", self.usage)
+        };
+        let admin = if self.admin { " (admin)" } else { "" };
+
+        format!("{usage} - {}{admin}", self.description)
+    }
+}
+
+macro_rules! command {
+    ($kind:ident, [$($name:literal),+], $usage:literal, $description:literal, $admin:literal) => {
+        CommandSpec {
+            kind: CommandKind::$kind,
+            names: &[$($name),+],
+            usage: $usage,
+            description: $description,
+            admin: $admin,
+        }
+    };
+}
+
+/// Every command, in the order of /help
+pub const COMMANDS: &[CommandSpec] = &[
+    command!(Help, ["help"], "", "list commands", false),
+    command!(
+        Memories,
+        ["memories", "mems"],
+        "[page]",
+        "list memories",
+        false
+    ),
+    command!(
+        AddMemory,
+        ["addmem", "addmemory"],
+        "<text>",
+        "save a memory",
+        true
+    ),
+    command!(
+        RemoveMemory,
+        ["rmem", "rmemory"],
+        "<index>",
+        "remove a memory",
+        true
+    ),
+    command!(Stats, ["stats"], "", "memory and message counts", false),
+    command!(
+        Forget,
+        ["forget", "clear"],
+        "",
+        "forget the conversation, memories stay",
+        true
+    ),
+    command!(
+        Activity,
+        ["activity"],
+        "",
+        "what happened here recently",
+        false
+    ),
+    command!(
+        LastError,
+        ["lasterror"],
+        "",
+        "the last failed generation",
+        false
+    ),
+    command!(
+        ClearError,
+        ["clearerror"],
+        "",
+        "forget the last error",
+        true
+    ),
+    command!(Transcript, ["transcript"], "", "the message history", true),
+    command!(
+        Consolidate,
+        ["consolidate"],
+        "[confirm|cancel]",
+        "merge duplicate memories",
+        true
+    ),
+    command!(
+        Relearn,
+        ["relearn"],
+        "[confirm|cancel]",
+        "learn memories from the history",
+        true
+    ),
+    command!(
+        SetPrompt,
+        ["setprompt"],
+        "<text>",
+        "override the system prompt",
+        true
+    ),
+    command!(ResetPrompt, ["resetprompt"], "", "undo /setprompt", true),
+    command!(
+        Model,
+        ["model"],
+        "[name]",
+        "show the model, admins can switch to another one",
+        false
+    ),
+    command!(WhoAmI, ["whoami"], "", "uids of everyone here", true),
+    command!(SaveMemories, ["savemem"], "", "save memories now", true),
+    command!(Rooms, ["rooms"], "", "list rooms", true),
+];
+
+/// A message starting with a slash and the name of one of the commands
+pub struct Command<'a> {
+    pub spec: &'static CommandSpec,
+    // everything after the name, trimmed, newlines included
+    pub rest: &'a str,
+}
+
+impl<'a> Command<'a> {
+    /// Other messages starting with a slash ("/me waves", "/r/rust") aren't commands
+    pub fn parse(text: &'a str) -> Option<Self> {
+        let text = text.trim().strip_prefix('/')?;
+        let (name, rest) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        let spec = COMMANDS.iter().find(|spec| spec.names.contains(&name))?;
+
+        Some(Command {
+            spec,
+            rest: rest.trim(),
+        })
+    }
+    /// The arguments split on whitespace, except inside double quotes
+    pub fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        let mut current: Option<String> = None;
+        let mut quoted = false;
+        for c in self.rest.chars() {
+            match c {
+                '"' => {
+                    quoted = !quoted;
+                    // so that "" is an (empty) argument too
+                    current.get_or_insert_with(String::new);
+                }
+                c if c.is_whitespace() && !quoted => args.extend(current.take()),
+                c => current.get_or_insert_with(String::new).push(c),
+            }
+        }
+        args.extend(current);
+
+        args
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        let command = Command::parse("  /setprompt You are\na bot ").unwrap();
+        assert_eq!(command.spec.kind, CommandKind::SetPrompt);
+        assert_eq!(command.rest, "You are\na bot");
+
+        let command = Command::parse("/mems 2").unwrap();
+        assert_eq!(command.spec.kind, CommandKind::Memories);
+        assert_eq!(
+            command.spec.help_line(),
+            "/memories, /mems [page] - list memories"
+        );
+        assert!(Command::parse("/memsx").is_none());
+        assert!(Command::parse("/me waves").is_none());
+        assert!(Command::parse("hey, what does /help do?").is_none());
+
+        let command = Command::parse("/rmem 1").unwrap();
+        assert_eq!(
+            command.spec.help_line(),
+            "/rmem, /rmemory <index> - remove a memory (admin)"
+        );
+    }
+
+    #[test]
+    fn splits_quoted_args() {
+        let command = Command::parse(r#"/addmem 2 "multi word"  plain "" x"y z""#).unwrap();
+        assert_eq!(command.args(), ["2", "multi word", "plain", "", "xy z"]);
+    }
+}
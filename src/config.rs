@@ -1,7 +1,15 @@
+use crate::ollama_api::OllamaRequestOptions;
+use anyhow::{ensure, Context};
+use chrono::format::{Item, StrftimeItems};
+use chrono_tz::Tz;
 use multichat_client::proto::AccessToken;
+use regex::Regex;
+use serde::de::{self, Deserializer};
 use serde::Deserialize;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
 use std::path::PathBuf;
+use toml::{Table, Value};
 use url::Url;
 
 #[derive(Deserialize)]
@@ -9,6 +17,75 @@ use url::Url;
 pub struct Config {
     pub multichat: Multichat,
     pub ollama: Ollama,
+    pub metrics: Option<Metrics>,
+}
+
+impl Config {
+    /// Catches values that parse fine, but would only fail later or misbehave. In the repl,
+    /// the multichat groups aren't used
+    pub fn validate(&self, repl: bool) -> anyhow::Result<()> {
+        let multichat = &self.multichat;
+        let ollama = &self.ollama;
+
+        ensure!(
+            !multichat.user_name.trim().is_empty(),
+            "multichat.user-name can't be empty"
+        );
+        ensure!(
+            repl || !multichat.groups.is_empty(),
+            "multichat.groups needs at least one group"
+        );
+        ensure!(
+            matches!(ollama.base_url.scheme(), "http" | "https"),
+            "ollama.base-url must be an http or https url, not {}",
+            ollama.base_url
+        );
+        ensure!(
+            !ollama.mention_name.trim().is_empty(),
+            "ollama.mention-name can't be empty"
+        );
+        ensure!(
+            ollama.prompt_messages_n > 0,
+            "ollama.prompt-messages-n must be at least 1"
+        );
+        // an empty tag would match everywhere
+        ensure!(
+            !ollama.memory_open_tag.is_empty() && !ollama.memory_close_tag.is_empty(),
+            "ollama.memory-open-tag and ollama.memory-close-tag can't be empty"
+        );
+        for (group, overrides) in &ollama.groups {
+            ensure!(
+                overrides
+                    .mention_name
+                    .as_ref()
+                    .is_none_or(|name| !name.trim().is_empty()),
+                "ollama.groups.{group}.mention-name can't be empty"
+            );
+        }
+        // an invalid format would only be noticed when formatting, by panicking
+        ensure!(
+            !StrftimeItems::new(&ollama.current_time_format).any(|item| item == Item::Error),
+            "ollama.current-time-format is not a valid format"
+        );
+        if let TimestampFormat::Absolute(pattern) = &ollama.timestamp_format {
+            ensure!(
+                !StrftimeItems::new(pattern).any(|item| item == Item::Error),
+                "ollama.timestamp-format is not a valid format"
+            );
+        }
+        serde_json::to_string(&OllamaRequestOptions::new(ollama))
+            .context("ollama.extra-options can't be sent to ollama")?;
+        ensure!(
+            ollama.downscale_images_to.is_none() || cfg!(feature = "downscale"),
+            "ollama.downscale-images-to needs the downscale feature, build with --features downscale"
+        );
+        ensure!(
+            ollama.downscale_images_to != Some(0),
+            "ollama.downscale-images-to must be at least 1"
+        );
+
+        Ok(())
+    }
 }
 
 #[derive(Deserialize)]
@@ -19,21 +96,502 @@ pub struct Multichat {
     pub certificate: Option<PathBuf>,
     pub user_name: String,
     pub groups: HashSet<String>,
+    // make a room for any other group that updates come from, instead of ignoring them
+    #[serde(default)]
+    pub auto_join_groups: bool,
+    // users allowed to run admin commands. everyone is, if not set
+    pub admin_users: Option<HashSet<String>>,
 }
 
 #[derive(Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct Ollama {
     pub memory_file: PathBuf,
+    // JSONL log of every prompt and response
+    pub audit_file: Option<PathBuf>,
+    #[serde(default = "default_true")]
+    pub audit_redact_images: bool,
+    #[serde(default)]
+    pub memory_format: MemoryFormat,
+    // what the model wraps new memories in
+    #[serde(default = "default_memory_open_tag")]
+    pub memory_open_tag: String,
+    #[serde(default = "default_memory_close_tag")]
+    pub memory_close_tag: String,
+    // how each memory is listed in {memories}, with {index} and {text}
+    #[serde(default = "default_memory_template")]
+    pub memory_template: String,
+    // only put the memories most similar to the latest message in {memories}, at most this many
+    pub memory_retrieval_top_k: Option<usize>,
+    // the model the similarity is judged with
+    #[serde(default = "default_embedding_model")]
+    pub embedding_model: String,
+    // how many memories /mems shows at once
+    #[serde(default = "default_memories_page_size")]
+    pub memories_page_size: usize,
+    // most memories /relearn adds at once
+    #[serde(default = "default_max_relearned_memories")]
+    pub max_relearned_memories: usize,
+    // how many recent events each room keeps for /activity
+    #[serde(default = "default_activity_log_size")]
+    pub activity_log_size: usize,
     pub basic_auth_user: String,
     pub basic_auth_password: String,
     pub base_url: Url,
+    // have ollama stream the response. multichat messages can't be edited, so the reply is
+    // still sent once it's complete, but the connection isn't idle during long generations
+    #[serde(default)]
+    pub stream: bool,
+    // how long a generation request may take before it's given up on
+    #[serde(default = "default_ollama_timeout_secs")]
+    pub ollama_timeout_secs: u64,
+    // retries with exponential backoff when ollama can't be connected to (including DNS failures)
+    #[serde(default = "default_connect_retries")]
+    pub connect_retries: u32,
+    #[serde(default = "default_connect_retry_backoff_ms")]
+    pub connect_retry_backoff_ms: u64,
+    // the same for server errors and connections dropped mid-request, counted separately
+    #[serde(default = "default_ollama_max_retries")]
+    pub ollama_max_retries: u32,
+    #[serde(default = "default_ollama_retry_backoff_ms")]
+    pub ollama_retry_backoff_ms: u64,
+    // limit on requests to ollama across all rooms, waiting requests go once there's room
+    pub requests_per_minute: Option<u32>,
     pub mention_name: String,
+    // when set, a message mentions the bot if it matches this, instead of containing the mention name
+    #[serde(default, deserialize_with = "deserialize_regex")]
+    pub mention_regex: Option<Regex>,
     pub model: String,
-    pub system_prompt: String,
+    // IANA name of the timezone absolute timestamps are shown in
+    #[serde(default = "default_timezone")]
+    pub timezone: Tz,
+    // strftime format of {current_time} in prompts
+    #[serde(default = "default_current_time_format")]
+    pub current_time_format: String,
+    // how the history messages are timestamped in the prompt
+    #[serde(default)]
+    pub timestamp_format: TimestampFormat,
+    // messages older than this don't get a relative timestamp in the prompt
+    pub relative_time_max_secs: Option<u64>,
+    // what they get instead
+    #[serde(default)]
+    pub old_timestamps: OldTimestamps,
+    // how long ollama keeps the model loaded after a request
+    #[serde(default = "default_keep_alive")]
+    pub keep_alive: String,
+    // load the model on startup, so the first response isn't slowed down by it
+    #[serde(default)]
+    pub warmup: bool,
+    pub system_prompt: SystemPrompt,
+    // send the segments of a list system prompt as one message instead of one message each
+    #[serde(default)]
+    pub merge_system_prompt: bool,
+    // added after the system prompt in rooms with no memories where the bot hasn't said anything yet
+    pub cold_start_prompt: Option<String>,
+    // the generate endpoint gets the conversation as plain text, for models that aren't chat-tuned
+    #[serde(default)]
+    pub endpoint: Endpoint,
+    // template the conversation here and use the raw generate endpoint, instead of the chat endpoint
+    pub chat_template: Option<ChatTemplate>,
+    // injected after the history, right before generating
+    pub reminder_prompt: Option<String>,
+    #[serde(default)]
+    pub reminder_role: PromptRole,
+    // chime in on its own after this many messages without saying anything
+    pub proactive_every_n_messages: Option<u64>,
+    #[serde(default = "default_proactive_prompt")]
+    pub proactive_prompt: String,
+    // ignore a message that's identical to the previous one from the same user, commands aside
+    #[serde(default)]
+    pub dedup_repeated_messages: bool,
+    // don't chime in or keep generating once everyone else has left the room
+    #[serde(default = "default_true")]
+    pub quiet_when_alone: bool,
     pub prompt_messages_n: usize,
+    // which messages are dropped once there's more than prompt-messages-n of them
+    #[serde(default)]
+    pub history_strategy: HistoryStrategy,
+    // how many of the first messages the sample strategy keeps
+    #[serde(default = "default_history_head_messages")]
+    pub history_head_messages: usize,
+    // how many attachments of a single message are downloaded when looking for an image
+    pub max_attachment_downloads: Option<usize>,
+    // stop downloading a message's attachments after this long and use what was found so far
+    pub attachment_time_budget_ms: Option<u64>,
+    // most images taken from a single message
+    #[serde(default = "default_max_images_per_message")]
+    pub max_images_per_message: usize,
+    // images bigger than this are skipped, before base64 encoding
+    pub max_image_bytes: Option<usize>,
+    // instead of skipping them, shrink them to this many pixels on the longer side.
+    // needs the downscale cargo feature
+    pub downscale_images_to: Option<u32>,
+    // what to do with an image that downscaling failed on, or couldn't shrink enough
+    #[serde(default)]
+    pub image_failure_action: ImageFailureAction,
+    #[serde(default = "default_image_failure_message")]
+    pub image_failure_message: String,
+    // how many of the most recent messages with images keep them in the history, older ones are dropped
+    #[serde(default = "default_max_history_images")]
+    pub max_history_images: usize,
+    // budget for the (base64) image data kept in the histories of all rooms together
+    pub max_image_memory_bytes: Option<usize>,
+    // rooms idle for this long have their history saved next to the memory file and dropped
+    // from memory, until the next message there
+    pub unload_idle_rooms_after_secs: Option<u64>,
     pub temperature: f32,
     pub top_k: u32,
+    pub top_p: Option<f32>,
+    pub repeat_penalty: Option<f32>,
+    // context window size, in tokens
+    pub num_ctx: Option<u32>,
+    // makes generations reproducible
+    pub seed: Option<i64>,
+    // passed to ollama as request options, for the ones that don't have their own setting
+    #[serde(default)]
+    pub extra_options: HashMap<String, serde_json::Value>,
+    // sent as the bot's first message before the history, to set the tone. not sent if not set
+    pub assistant_primer: Option<String>,
+    // stop sending the primer once the history has this many messages
+    pub primer_max_history: Option<usize>,
+    // models and groups for which the assistant primer message is not sent
+    #[serde(default)]
+    pub no_primer_models: HashSet<String>,
+    #[serde(default)]
+    pub no_primer_groups: HashSet<String>,
+    // drop a finished response if the conversation has moved on too much since it was started
+    pub stale_after_messages: Option<u64>,
+    pub stale_after_secs: Option<u64>,
+    // after a failed generation, ignore mentions for this long, doubling with each failure in a row
+    pub error_cooldown_secs: Option<u64>,
+    #[serde(default = "default_error_cooldown_max_secs")]
+    pub error_cooldown_max_secs: u64,
+    // multichat shows messages as plain text, so markdown in replies would appear literally
+    #[serde(default)]
+    pub strip_markdown: bool,
+    // send each paragraph of a reply as a message of its own
+    #[serde(default = "default_true")]
+    pub split_on_double_newline: bool,
+    // longer messages are split, at whitespace where possible
+    pub max_message_chars: Option<usize>,
+    // remove the <think> blocks of reasoning models from replies
+    #[serde(default = "default_true")]
+    pub strip_think_tags: bool,
+    // lines left at most this long after removing a memory from them are dropped, like
+    // "I'll remember that:". 0 only drops the lines left empty
+    #[serde(default)]
+    pub memory_leadin_max_chars: usize,
+    // phrases removed from replies, like "As {mention_name}, ". matched case-insensitively
+    #[serde(default)]
+    pub self_reference_patterns: Vec<String>,
+    // what the mention name is replaced with when the bot says it in a reply, "" removes it
+    pub self_mention_replacement: Option<String>,
+    // regexes that generated messages are checked against before being sent
+    #[serde(default, deserialize_with = "deserialize_regexes")]
+    pub output_blocklist: Vec<Regex>,
+    // like output-blocklist, but matched literally and case-insensitively
+    #[serde(default, deserialize_with = "deserialize_phrases")]
+    pub output_blocked_phrases: Vec<Regex>,
+    #[serde(default)]
+    pub output_block_action: BlockAction,
+    #[serde(default = "default_output_block_message")]
+    pub output_block_message: String,
+    // the reply is held back until at least this much time has passed since the request,
+    // plus some time per character to simulate typing
+    #[serde(default)]
+    pub min_reply_delay_ms: u64,
+    #[serde(default)]
+    pub typing_ms_per_char: u64,
+    // wait for the room to be quiet for this long before generating, so bursts of messages
+    // are coalesced into a single generation
+    pub debounce_ms: Option<u64>,
+    // the same for restarting a generation because of a message that doesn't mention the bot
+    pub regenerate_debounce_ms: Option<u64>,
+    // least time between the starts of two generations in a group, later ones are postponed
+    pub min_generation_interval_secs: Option<u64>,
+    // sent right away when the bot is mentioned, so people know a reply is coming. multichat
+    // messages can't be edited or deleted, so it stays in the chat
+    pub thinking_message: Option<String>,
+    // group name -> settings that override the ones above for that group
+    #[serde(default)]
+    pub groups: HashMap<String, GroupOverrides>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct GroupOverrides {
+    pub mention_name: Option<String>,
+    // keep track of the conversation but never post anything
+    #[serde(default)]
+    pub observe_only: bool,
+    pub keep_alive: Option<String>,
+    pub warmup: Option<bool>,
+    pub model: Option<String>,
+    pub system_prompt: Option<SystemPrompt>,
+    pub temperature: Option<f32>,
+    pub top_k: Option<u32>,
+    // whether the bot may save memories from this group, e.g. off for untrusted ones
+    #[serde(default = "default_true")]
+    pub allow_memory_creation: bool,
+}
+
+/// Which ollama endpoint generates the replies
+#[derive(Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Endpoint {
+    #[default]
+    Chat,
+    Generate,
+}
+
+/// Built-in chat template presets
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChatTemplate {
+    Chatml,
+    Llama3,
+    Gemma,
+}
+
+/// The system prompt, or an ordered list of segments of it
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum SystemPrompt {
+    Single(String),
+    Segments(Vec<String>),
+}
+
+impl SystemPrompt {
+    pub fn segments(&self) -> &[String] {
+        match self {
+            SystemPrompt::Single(prompt) => std::slice::from_ref(prompt),
+            SystemPrompt::Segments(segments) => segments,
+        }
+    }
+}
+
+/// Which messages are dropped from a full history
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum HistoryStrategy {
+    /// The oldest ones
+    #[default]
+    Recent,
+    /// The oldest ones after the first few, keeping the opening of the conversation
+    Sample,
+}
+
+/// How messages in the prompt are timestamped
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum TimestampFormat {
+    // "a few minutes ago"
+    #[default]
+    Humanized,
+    // a strftime pattern
+    Absolute(String),
+    None,
+}
+
+/// How messages too old for a relative timestamp are shown
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum OldTimestamps {
+    #[default]
+    Date,
+    Hide,
+}
+
+/// Role of an injected prompt message
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum PromptRole {
+    #[default]
+    System,
+    User,
+}
+
+impl PromptRole {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PromptRole::System => "system",
+            PromptRole::User => "user",
+        }
+    }
+}
+
+/// Serialization format of the memory file
+#[derive(Deserialize, Default, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MemoryFormat {
+    #[default]
+    Json,
+    Yaml,
+    Toml,
+}
+
+/// What to do with a generated reply that matches the output blocklist
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum BlockAction {
+    /// Replace the matched text with asterisks
+    #[default]
+    Redact,
+    /// Don't send the messages that match
+    Skip,
+    /// Send `output_block_message` instead of the whole reply
+    Replace,
+}
+
+/// What to do with an image that couldn't be downscaled to fit max-image-bytes
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum ImageFailureAction {
+    /// Leave the image out
+    #[default]
+    Skip,
+    /// Send the image as it was, over max-image-bytes
+    SendOriginal,
+    /// Leave the image out and reply with `image_failure_message`
+    Reply,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Metrics {
+    pub listen: SocketAddr,
+}
+
+/// Deep-merges `overlay` into `base`, with values from `overlay` taking precedence.
+/// Tables are merged recursively, anything else (including arrays) is replaced.
+pub fn merge(base: &mut Table, overlay: Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base_table)), Value::Table(overlay_table)) => {
+                merge(base_table, overlay_table);
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_memory_open_tag() -> String {
+    "<MEMORY>".to_owned()
+}
+
+fn default_memory_close_tag() -> String {
+    "</MEMORY>".to_owned()
+}
+
+fn default_memory_template() -> String {
+    "- {text}".to_owned()
+}
+
+fn default_embedding_model() -> String {
+    "nomic-embed-text".to_owned()
+}
+
+fn default_memories_page_size() -> usize {
+    20
+}
+
+fn default_max_relearned_memories() -> usize {
+    20
+}
+
+fn default_activity_log_size() -> usize {
+    50
+}
+
+fn default_proactive_prompt() -> String {
+    "Nobody has mentioned {mention_name} in a while. {mention_name} chimes in to the conversation naturally."
+        .to_owned()
+}
+
+fn default_timezone() -> Tz {
+    Tz::UTC
+}
+
+fn default_current_time_format() -> String {
+    "%A, %Y-%m-%d %H:%M %Z".to_owned()
+}
+
+fn default_keep_alive() -> String {
+    "30s".to_owned()
+}
+
+fn default_connect_retries() -> u32 {
+    3
+}
+
+fn default_ollama_max_retries() -> u32 {
+    2
+}
+
+fn default_ollama_retry_backoff_ms() -> u64 {
+    1000
+}
+
+fn default_connect_retry_backoff_ms() -> u64 {
+    500
+}
+
+fn default_history_head_messages() -> usize {
+    4
+}
+
+fn default_max_images_per_message() -> usize {
+    4
+}
+
+fn default_max_history_images() -> usize {
+    1
+}
+
+fn default_ollama_timeout_secs() -> u64 {
+    120
+}
+
+fn default_error_cooldown_max_secs() -> u64 {
+    600
+}
+
+fn default_image_failure_message() -> String {
+    "I couldn't look at that image.".to_owned()
+}
+
+fn default_output_block_message() -> String {
+    "I can't say that.".to_owned()
+}
+
+fn deserialize_regexes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Regex>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|pattern| Regex::new(pattern).map_err(de::Error::custom))
+        .collect()
+}
+
+fn deserialize_phrases<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Regex>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|phrase| {
+            Regex::new(&format!("(?i){}", regex::escape(phrase))).map_err(de::Error::custom)
+        })
+        .collect()
+}
+
+fn deserialize_regex<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Regex>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|pattern| Regex::new(&pattern).map_err(de::Error::custom))
+        .transpose()
 }
 
 #[cfg(test)]
@@ -43,6 +601,62 @@ mod tests {
     #[test]
     fn example_parses() {
         let config = include_str!("../example/config.toml");
-        toml::from_str::<Config>(config).unwrap();
+        toml::from_str::<Config>(config)
+            .unwrap()
+            .validate(false)
+            .unwrap();
+    }
+
+    #[test]
+    fn rejects_empty_history() {
+        let config = include_str!("../example/config.toml")
+            .replace("prompt-messages-n = 40", "prompt-messages-n = 0");
+
+        let error = toml::from_str::<Config>(&config)
+            .unwrap()
+            .validate(false)
+            .unwrap_err();
+        assert!(error.to_string().contains("prompt-messages-n"));
+    }
+
+    #[test]
+    fn blocked_phrases_are_literal() {
+        let config = include_str!("../example/config.toml").replace(
+            "[ollama]\n",
+            "[ollama]\noutput-blocked-phrases = [\"(sudo) rm\"]\n",
+        );
+
+        let config = toml::from_str::<Config>(&config).unwrap();
+        let phrase = &config.ollama.output_blocked_phrases[0];
+        assert!(phrase.is_match("just (SUDO) RM it"));
+        assert!(!phrase.is_match("just sudo rm it"));
+    }
+
+    #[test]
+    fn merge_overrides_nested() {
+        let mut base = toml::from_str::<Table>(
+            r#"
+            [ollama]
+            model = "a"
+            top-k = 10
+            groups = ["x", "y"]
+            "#,
+        )
+        .unwrap();
+        let overlay = toml::from_str::<Table>(
+            r#"
+            [ollama]
+            model = "b"
+            groups = ["z"]
+            "#,
+        )
+        .unwrap();
+
+        merge(&mut base, overlay);
+
+        let ollama = base["ollama"].as_table().unwrap();
+        assert_eq!(ollama["model"].as_str(), Some("b"));
+        assert_eq!(ollama["top-k"].as_integer(), Some(10));
+        assert_eq!(ollama["groups"].as_array().unwrap().len(), 1);
     }
 }
@@ -0,0 +1,25 @@
+/// Shrinks an image so that neither side is longer than max_dimension, re-encoded as a jpeg
+#[cfg(feature = "downscale")]
+pub fn downscale(bytes: &[u8], max_dimension: u32) -> anyhow::Result<Vec<u8>> {
+    use anyhow::Context;
+    use image::{imageops::FilterType, DynamicImage, ImageFormat};
+    use std::io::Cursor;
+
+    let mut image = image::load_from_memory(bytes).context("decoding image")?;
+    if image.width() > max_dimension || image.height() > max_dimension {
+        image = image.resize(max_dimension, max_dimension, FilterType::Triangle);
+    }
+
+    // jpeg has no alpha channel
+    let mut encoded = Cursor::new(Vec::new());
+    DynamicImage::ImageRgb8(image.to_rgb8())
+        .write_to(&mut encoded, ImageFormat::Jpeg)
+        .context("encoding image")?;
+
+    Ok(encoded.into_inner())
+}
+
+#[cfg(not(feature = "downscale"))]
+pub fn downscale(_bytes: &[u8], _max_dimension: u32) -> anyhow::Result<Vec<u8>> {
+    anyhow::bail!("built without the downscale feature")
+}
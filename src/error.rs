@@ -0,0 +1,30 @@
+use thiserror::Error;
+
+/// Errors from handling an event, classified so the main loop can decide whether to carry on
+#[derive(Error, Debug)]
+pub enum Error {
+    /// Talking to the multichat server failed, the connection is most likely gone
+    #[error("multichat connection: {0:#}")]
+    MultichatConnection(anyhow::Error),
+    /// Retrying won't help, like with a rejected access token or a group that doesn't exist
+    #[error("{0:#}")]
+    Fatal(anyhow::Error),
+    /// Saving the memory file failed
+    #[error("saving memories: {0:#}")]
+    Memory(anyhow::Error),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl Error {
+    pub fn multichat(e: impl Into<anyhow::Error>) -> Self {
+        Self::MultichatConnection(e.into())
+    }
+    /// Whether the connection to multichat has to be established again
+    pub fn is_connection_lost(&self) -> bool {
+        matches!(self, Self::MultichatConnection(_))
+    }
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, Self::Fatal(_))
+    }
+}
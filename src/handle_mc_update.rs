@@ -1,14 +1,37 @@
 use crate::{
-    ollama_api::{OllamaRequest, OllamaRequestMessage, OllamaRequestOptions, OllamaResponse},
-    room_state::Message,
-    State,
+    chat_template,
+    command::{Command, CommandKind, CommandSpec, COMMANDS},
+    config::{Endpoint, ImageFailureAction, Ollama},
+    downscale::downscale,
+    error::Error,
+    handle_ollama_gen::split_long_message,
+    memory_retrieval::Retrieval,
+    ollama_api::{
+        GenerateRequest, OllamaRequest, OllamaRequestMessage, OllamaRequestOptions, RequestBody,
+    },
+    ollama_client::ResponseFuture,
+    room_state::{Message, RoomState},
+    State, TaskKind,
 };
-use anyhow::Context;
+use anyhow::{ensure, Context};
+use chrono::Utc;
+use chrono_humanize::HumanTime;
 use multichat_client::{Update, UpdateKind};
-use tokio::task::JoinHandle;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+use tracing::{debug, warn};
 
-pub async fn handle_mc_update(state: &mut State, update: Update) -> anyhow::Result<()> {
-    // some convenience macros
+const RELEARN_PROMPT: &str = "Below is a chat transcript. \
+    Extract the facts about the people in it that are worth remembering long term. \
+    Reply only with the facts, one per line, without numbering or any other text. \
+    Reply with nothing if there is nothing worth remembering.";
+const CONSOLIDATION_PROMPT: &str = "Below is a list of saved memories. \
+    Merge duplicate and overlapping memories and tighten their wording, without losing any information. \
+    Reply only with the resulting memories, one per line, without numbering or any other text.";
+
+pub async fn handle_mc_update(state: &mut State, update: Update) -> Result<(), Error> {
     macro_rules! room {
         () => {
             state
@@ -17,12 +40,15 @@ pub async fn handle_mc_update(state: &mut State, update: Update) -> anyhow::Resu
                 .context("received update for group im not in")?
         };
     }
-    macro_rules! send {
-        ($msg:expr) => {
-            state
-                .mc_client
-                .send_message(update.gid, room!().my_uid, $msg, &[])
-        };
+
+    if !state.rooms.contains_key(&update.gid) {
+        if !state.config.multichat.auto_join_groups {
+            // can happen transiently while joining or leaving groups
+            debug!("Ignoring update for group {} that im not in", update.gid);
+            return Ok(());
+        }
+
+        state.add_room(update.gid).await?;
     }
 
     match update.kind {
@@ -31,6 +57,11 @@ pub async fn handle_mc_update(state: &mut State, update: Update) -> anyhow::Resu
         }
         UpdateKind::Leave => {
             room!().usernames.remove(&update.uid);
+
+            if state.config.ollama.quiet_when_alone && room!().is_alone() {
+                // nobody left to reply to
+                room!().cancel_generation();
+            }
         }
         UpdateKind::Message(message) => {
             if room!().my_uid == update.uid {
@@ -38,74 +69,629 @@ pub async fn handle_mc_update(state: &mut State, update: Update) -> anyhow::Resu
                 return Ok(());
             }
 
-            let mut image = None;
-            for attachment in &message.attachments {
-                let bytes = state.mc_client.download_attachment(attachment.id).await?;
-                // only save image types
-                let is_image = match bytes.as_slice() {
-                    [0xFF, 0xD8, 0xFF, ..]
-                    | [0x89, b'P', b'N', b'G', ..]
-                    | [0x52, 0x49, 0x46, 0x46, ..] => true,
-                    _ => false,
-                };
-                if is_image {
-                    image = Some(bytes);
+            // repeating a command is on purpose, e.g. /mems after /rmem
+            let is_command = Command::parse(&message.message).is_some();
+            if state.config.ollama.dedup_repeated_messages && !is_command {
+                let previous = room!()
+                    .last_message_by_user
+                    .insert(update.uid, message.message.clone());
+                if message.attachments.is_empty() && previous.as_ref() == Some(&message.message) {
+                    debug!(
+                        "Ignoring repeated message from user {} in group {}",
+                        update.uid, update.gid
+                    );
+                    return Ok(());
+                }
+            }
+
+            // downloads go through the single multichat connection one at a time,
+            // so the number of them is what bounds the latency
+            let max_downloads = state
+                .config
+                .ollama
+                .max_attachment_downloads
+                .unwrap_or(usize::MAX);
+
+            // checked between downloads, cancelling one midway would break the connection
+            let deadline = state
+                .config
+                .ollama
+                .attachment_time_budget_ms
+                .map(|budget| Instant::now() + Duration::from_millis(budget));
+
+            let mut images = Vec::new();
+            let mut image_failed = false;
+            for attachment in message.attachments.iter().take(max_downloads) {
+                if images.len() >= state.config.ollama.max_images_per_message {
+                    break;
+                }
+                if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    debug!("Out of time for downloading attachments, going on without the rest");
                     break;
                 }
+
+                let bytes = state
+                    .sink
+                    .client()
+                    .context("multichat update without a connection")?
+                    .download_attachment(attachment.id)
+                    .await
+                    .map_err(Error::multichat)?;
+                // only save image types
+                if detect_image_kind(&bytes).is_none() {
+                    continue;
+                }
+                let size = bytes.len();
+                match fit_image_size(&state.config.ollama, bytes).await {
+                    Ok(Some(bytes)) => images.push(bytes),
+                    Ok(None) => warn!(
+                        "Skipping attachment {} from user {} in group {}, {size} bytes is over max-image-bytes",
+                        attachment.id, update.uid, update.gid
+                    ),
+                    Err((e, original)) => {
+                        warn!(
+                            "Downscaling attachment {} from user {} in group {} failed. {e:#}",
+                            attachment.id, update.uid, update.gid
+                        );
+                        match state.config.ollama.image_failure_action {
+                            ImageFailureAction::Skip => {}
+                            ImageFailureAction::SendOriginal => images.push(original),
+                            ImageFailureAction::Reply => image_failed = true,
+                        }
+                    }
+                }
+            }
+            if image_failed {
+                let my_uid = room!().my_uid;
+                let reply = state.config.ollama.image_failure_message.clone();
+                state.send_message(update.gid, my_uid, &reply).await?;
+            }
+
+            handle_message(state, update.gid, update.uid, &message.message, images).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Keeps an image within max-image-bytes, by downscaling it if that's enabled. `None` if it's
+/// too big and downscaling isn't, the original along with the error if downscaling failed
+async fn fit_image_size(
+    config: &Ollama,
+    bytes: Vec<u8>,
+) -> Result<Option<Vec<u8>>, (anyhow::Error, Vec<u8>)> {
+    let size = bytes.len();
+    let Some(max_bytes) = config.max_image_bytes.filter(|max_bytes| size > *max_bytes) else {
+        return Ok(Some(bytes));
+    };
+    let Some(max_dimension) = config.downscale_images_to else {
+        return Ok(None);
+    };
+
+    // decoding and encoding is too slow to do on the runtime
+    let original = Arc::new(bytes);
+    let shared = original.clone();
+    let downscaled = tokio::task::spawn_blocking(move || downscale(&shared, max_dimension))
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|res| res)
+        .and_then(|bytes| {
+            ensure!(
+                bytes.len() <= max_bytes,
+                "still {} bytes after downscaling",
+                bytes.len()
+            );
+            Ok(bytes)
+        });
+    downscaled.map(Some).map_err(|e| {
+        let original = Arc::try_unwrap(original).unwrap_or_else(|original| (*original).clone());
+        (e, original)
+    })
+}
+
+/// Handles a message from a user, whether it came from multichat or the repl
+pub async fn handle_message(
+    state: &mut State,
+    gid: u32,
+    uid: u32,
+    text: &str,
+    images: Vec<Vec<u8>>,
+) -> Result<(), Error> {
+    // some convenience macros
+    macro_rules! room {
+        () => {
+            state
+                .rooms
+                .get_mut(&gid)
+                .context("received message for group im not in")?
+        };
+    }
+    macro_rules! send {
+        ($msg:expr) => {{
+            let my_uid = room!().my_uid;
+            state.send_message(gid, my_uid, $msg).await
+        }};
+    }
+    macro_rules! require_admin {
+        () => {
+            if !is_admin(state, gid, uid) {
+                send!("not authorized")?;
+
+                return Ok(());
+            }
+        };
+    }
+
+    // an idle room gets its history back before anything else
+    state.load_history(gid).await;
+
+    // commands aren't part of the conversation
+    let command = Command::parse(text);
+    if command.is_none() {
+        let username = room!().usernames.get(&uid).cloned();
+        state.push_message(gid, Message::new(text, false, username, images));
+    }
+
+    if room!().observe_only {
+        // only keep the history, never respond
+        return Ok(());
+    }
+
+    // handle the commands, even if they mention me
+    if let Some(command) = &command {
+        if command.spec.admin {
+            require_admin!();
+        }
+
+        match command.spec.kind {
+            CommandKind::Help => {
+                let help = COMMANDS
+                    .iter()
+                    .map(CommandSpec::help_line)
+                    .collect::<Vec<_>>()
+                    .join("\n");
+
+                send!(&help)?;
             }
+            CommandKind::Memories => {
+                let args = command.args();
+                let page = match args.first().map(|page| page.parse::<usize>()) {
+                    None => 1,
+                    Some(Ok(page)) if page > 0 => page,
+                    _ => {
+                        send!("/mems [page] - list memories")?;
 
-            state.push_message(update.gid, Message::new(&message.message, false, image));
+                        return Ok(());
+                    }
+                };
+                let page_size = state.config.ollama.memories_page_size.max(1);
 
-            // handle some commands
-            let trimmed = message.message.trim();
-            if trimmed.starts_with("/memories") || trimmed.starts_with("/mems") {
-                let formatted_mems = room!()
-                    .memories
+                let pages = room!().memories.len().div_ceil(page_size).max(1);
+                if page > pages {
+                    let reply = if pages == 1 {
+                        "there's only 1 page".to_owned()
+                    } else {
+                        format!("there are only {pages} pages")
+                    };
+                    send!(&reply)?;
+
+                    return Ok(());
+                }
+                let memories = &room!().memories;
+                let mut formatted_mems = memories
                     .iter()
                     .enumerate()
+                    .skip((page - 1) * page_size)
+                    .take(page_size)
                     .map(|(i, m)| format!("{i} - {m}\n"))
                     .collect::<String>();
+                if formatted_mems.is_empty() {
+                    formatted_mems.push_str("no memories here\n");
+                }
+                if pages > 1 {
+                    formatted_mems.push_str(&format!(
+                        "page {page}/{pages}, {} memories in total, /mems <page> for others",
+                        memories.len()
+                    ));
+                }
 
-                send!(&formatted_mems).await?;
+                send!(&formatted_mems)?;
+            }
+            CommandKind::Transcript => {
+                let transcript = room!()
+                    .message_history
+                    .iter()
+                    .map(|m| {
+                        m.format_transcript(
+                            &state.config.multichat.user_name,
+                            state.config.ollama.timezone,
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                if transcript.is_empty() {
+                    send!("history is empty")?;
 
-                return Ok(());
+                    return Ok(());
+                }
+
+                let parts = match state.config.ollama.max_message_chars {
+                    Some(max_chars) => split_long_message(&transcript, max_chars),
+                    None => vec![transcript],
+                };
+                for part in parts {
+                    send!(&part)?;
+                }
+            }
+            CommandKind::Consolidate => {
+                let args = command.args();
+                match args.first().map(String::as_str) {
+                    Some("confirm") => match room!().pending_consolidation.take() {
+                        Some(memories) => {
+                            state.replace_memories(gid, memories).await?;
+
+                            send!("memories replaced")?;
+                        }
+                        None => {
+                            send!("nothing to confirm, use /consolidate first")?;
+                        }
+                    },
+                    Some("cancel") => {
+                        room!().pending_consolidation = None;
+
+                        send!("consolidation cancelled")?;
+                    }
+                    _ => {
+                        if room!().memories.is_empty() {
+                            send!("no memories to consolidate")?;
+                        } else {
+                            let request = send_consolidation_request(state, gid)?;
+                            let task = state.spawn_task(gid, TaskKind::Consolidation, request);
+                            if let Some(outdated) = room!().consolidation_task.replace(task) {
+                                outdated.abort();
+                            }
+
+                            send!("consolidating memories...")?;
+                        }
+                    }
+                }
+            }
+            CommandKind::Relearn => {
+                let args = command.args();
+                match args.first().map(String::as_str) {
+                    Some("confirm") => match room!().pending_relearn.take() {
+                        Some(memories) => {
+                            state.add_memories(gid, memories).await?;
+
+                            send!("memories added")?;
+                        }
+                        None => {
+                            send!("nothing to confirm, use /relearn first")?;
+                        }
+                    },
+                    Some("cancel") => {
+                        room!().pending_relearn = None;
+
+                        send!("relearning cancelled")?;
+                    }
+                    _ => {
+                        if !room!().allow_memory_creation {
+                            send!("memory creation is disabled in this group")?;
+                        } else if room!().message_history.is_empty() {
+                            send!("no history to learn from")?;
+                        } else {
+                            let request = send_relearn_request(state, gid)?;
+                            let task = state.spawn_task(gid, TaskKind::Relearn, request);
+                            if let Some(outdated) = room!().relearn_task.replace(task) {
+                                outdated.abort();
+                            }
+
+                            send!("relearning memories from the history...")?;
+                        }
+                    }
+                }
+            }
+            CommandKind::SetPrompt => {
+                let prompt = command.rest;
+                if prompt.is_empty() {
+                    send!("/setprompt <text> - override the system prompt in this group")?;
+                } else {
+                    room!().system_prompt_override = Some(prompt.to_owned());
+
+                    send!("system prompt overridden, /resetprompt to revert")?;
+                }
+            }
+            CommandKind::Model => {
+                let Some(model) = command.args().first().cloned() else {
+                    let reply = format!("using {}", room!().settings.model);
+                    send!(&reply)?;
+
+                    return Ok(());
+                };
+
+                require_admin!();
+
+                // listing the models can take a while, the result is handled in the main loop
+                let ollama = state.ollama.clone();
+                let request = async move {
+                    let models = ollama
+                        .list_models()
+                        .await
+                        .context("failed to list models")?;
+                    // ollama lists the tag even when it's the default one
+                    let tagged = if model.contains(':') {
+                        model.clone()
+                    } else {
+                        format!("{model}:latest")
+                    };
+                    ensure!(
+                        models.contains(&model) || models.contains(&tagged),
+                        "ollama doesn't have {model}, pick one of: {}",
+                        models.join(", ")
+                    );
+
+                    Ok(model)
+                };
+                let task = state.spawn_task(gid, TaskKind::ModelSwitch, request);
+                if let Some(outdated) = room!().model_switch_task.replace(task) {
+                    outdated.abort();
+                }
+            }
+            CommandKind::ResetPrompt => {
+                room!().system_prompt_override = None;
+
+                send!("system prompt reset")?;
             }
-            if trimmed.starts_with("/rmem") || trimmed.starts_with("/rmemory") {
-                if let Some(idx) = message.message.trim().split_whitespace().nth(1) {
+            CommandKind::LastError => {
+                let reply = match &room!().last_error {
+                    Some((time, error)) => format!("{} - {error}", HumanTime::from(*time)),
+                    None => "no errors".to_string(),
+                };
+
+                send!(&reply)?;
+            }
+            CommandKind::WhoAmI => {
+                let room = room!();
+                let mut usernames: Vec<_> = room.usernames.iter().collect();
+                usernames.sort();
+                let reply = format!(
+                    "my uid: {}\n{}",
+                    room.my_uid,
+                    usernames
+                        .into_iter()
+                        .map(|(uid, name)| format!("{uid} - {name}"))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                );
+
+                send!(&reply)?;
+            }
+            CommandKind::SaveMemories => {
+                let reply = match state.flush_memories().await {
+                    Ok(()) => "memories saved".to_string(),
+                    Err(e) => format!("failed to save memories. {e}"),
+                };
+
+                send!(&reply)?;
+            }
+            CommandKind::Rooms => {
+                let mut rooms: Vec<_> = state.rooms.iter().collect();
+                rooms.sort_by_key(|(gid, _room)| **gid);
+                let reply = rooms
+                    .into_iter()
+                    .map(|(gid, room)| {
+                        let mode = if room.observe_only {
+                            "observing"
+                        } else {
+                            "active"
+                        };
+                        let generating = if room.ollama_api_task.is_some() {
+                            ", generating"
+                        } else {
+                            ""
+                        };
+                        format!(
+                            "{gid} - {} ({mode}{generating}), {} memories, {} messages",
+                            room.room_name,
+                            room.memories.len(),
+                            room.message_history.len()
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n");
+
+                send!(&reply)?;
+            }
+            CommandKind::Stats => {
+                let persistence = if state.memories_read_only {
+                    "not saved, the memory file is read-only"
+                } else {
+                    "saved"
+                };
+                let image_memory = state.image_memory();
+                let reply = format!(
+                    "memories: {} ({persistence})\nmessages received: {}\nimage memory: {} KiB",
+                    room!().memories.len(),
+                    room!().received_messages,
+                    image_memory / 1024,
+                );
+
+                send!(&reply)?;
+            }
+            CommandKind::Activity => {
+                let reply = room!()
+                    .activity
+                    .iter()
+                    .map(|(time, event)| format!("{} - {event}", HumanTime::from(*time)))
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                let reply = if reply.is_empty() {
+                    "no activity yet".to_string()
+                } else {
+                    reply
+                };
+
+                send!(&reply)?;
+            }
+            CommandKind::Forget => {
+                // the command itself was just pushed, so it goes too
+                let room = room!();
+                room.message_history.clear();
+                // a reply in the making would be based on the forgotten conversation
+                room.cancel_generation();
+
+                send!("history cleared")?;
+            }
+            CommandKind::ClearError => {
+                room!().last_error = None;
+
+                send!("error cleared")?;
+            }
+            CommandKind::AddMemory => {
+                let memory = command.rest;
+                if memory.is_empty() {
+                    send!("/addmem <text> - save a memory")?;
+                } else if !room!().allow_memory_creation {
+                    send!("memory creation is disabled in this group")?;
+                } else {
+                    state.add_memory(gid, memory.to_owned()).await?;
+
+                    send!("memory saved")?;
+                }
+            }
+            CommandKind::RemoveMemory => {
+                let args = command.args();
+                if let Some(idx) = args.first() {
                     match idx.parse::<usize>() {
                         Err(e) => {
-                            send!(&format!("{e:?}")).await?;
+                            send!(&format!("{e:?}"))?;
                         }
                         Ok(idx) => {
                             if idx >= room!().memories.len() {
-                                send!("invalid id, use /mems to list").await?;
+                                send!("invalid id, use /mems to list")?;
                             } else {
-                                let memory = state.remove_memory(update.gid, idx).await?;
+                                let memory = state.remove_memory(gid, idx).await?;
 
-                                send!(&format!("removed {memory:?}")).await?;
+                                send!(&format!("removed {memory:?}"))?;
                             }
                         }
                     }
                 } else {
-                    send!("/rmem <index> - remove a memory (/mems to list)").await?;
+                    send!("/rmem <index> - remove a memory (/mems to list)")?;
                 }
-
-                return Ok(());
             }
+        }
 
-            // check if this new message mentions the bot
-            if is_substring_isolated(&message.message, &state.config.ollama.mention_name) {
-                // if this message mentions the bot, generate a response
-                room!().ollama_api_task = Some(send_ollama_request(state, update.gid)?);
-            } else {
-                // if it doesnt mention the bot, but the bot is currently generating a response, start generating
-                // it again with the new message
-                if room!().ollama_api_task.is_some() {
-                    room!().ollama_api_task = Some(send_ollama_request(state, update.gid)?);
-                }
+        return Ok(());
+    }
+
+    // check if this new message mentions the bot
+    let mentioned = match &state.config.ollama.mention_regex {
+        Some(mention_regex) => mention_regex.is_match(text),
+        None => is_substring_isolated(text, &room!().mention_name),
+    };
+    // ollama has been failing here, dont spam the room with more errors
+    if room!()
+        .error_cooldown
+        .is_some_and(|until| Instant::now() < until)
+    {
+        if mentioned && !room!().cooldown_notice_sent {
+            room!().cooldown_notice_sent = true;
+
+            send!("still having issues, try again in a bit")?;
+        }
+
+        return Ok(());
+    }
+    if mentioned {
+        // if this message mentions the bot, generate a response
+        let idle = room!().ollama_api_task.is_none() && room!().pending_generation.is_none();
+        if let Some(thinking_message) = state.config.ollama.thinking_message.clone() {
+            // once per reply, not for every mention while one is being generated
+            if idle {
+                send!(&thinking_message)?;
             }
         }
+        request_generation(state, gid, false)?;
+    } else {
+        // if it doesnt mention the bot, but the bot is currently generating a response (or is about to),
+        // start generating it again with the new message
+        if room!().ollama_api_task.is_some() || room!().pending_generation.is_some() {
+            request_generation(state, gid, true)?;
+        } else if let Some(n) = state.config.ollama.proactive_every_n_messages {
+            let quiet = state.config.ollama.quiet_when_alone && room!().is_alone();
+            // nobody has talked to the bot in a while, so chime in
+            if !quiet && room!().messages_since_reply >= n {
+                room!().messages_since_reply = 0;
+                start_generation(state, gid, true)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Starts generating a response right away, or if debouncing is enabled,
+/// (re)schedules it for when the room goes quiet. Regenerating because of a message that doesn't
+/// mention the bot has a debounce of its own. If the last generation started less than
+/// min-generation-interval-secs ago, it's scheduled for when the interval is over instead of
+/// being skipped, so that the latest messages still get a reply
+fn request_generation(state: &mut State, gid: u32, regenerating: bool) -> anyhow::Result<()> {
+    let room = state
+        .rooms
+        .get_mut(&gid)
+        .context("received update for group im not in")?;
+
+    let debounce_ms = match state.config.ollama.regenerate_debounce_ms {
+        Some(regenerate_debounce_ms) if regenerating => Some(regenerate_debounce_ms),
+        _ => state.config.ollama.debounce_ms,
+    };
+    let debounced =
+        debounce_ms.map(|debounce_ms| Instant::now() + Duration::from_millis(debounce_ms));
+    let earliest = state
+        .config
+        .ollama
+        .min_generation_interval_secs
+        .zip(room.last_generation_start)
+        .map(|(secs, last)| last + Duration::from_secs(secs))
+        .filter(|earliest| *earliest > Instant::now());
+    let due = match (debounced, earliest) {
+        (None, None) => return start_generation(state, gid, false),
+        (Some(due), None) | (None, Some(due)) => due,
+        (Some(debounced), Some(earliest)) => debounced.max(earliest),
+    };
+
+    // a generation that's already running would be outdated by then
+    room.cancel_generation();
+    room.pending_generation = Some(due);
+
+    Ok(())
+}
+
+/// Starts generating a response. A proactive one isn't prompted by a mention,
+/// the bot chimes in on its own
+pub fn start_generation(state: &mut State, gid: u32, proactive: bool) -> anyhow::Result<()> {
+    let request = send_ollama_request(state, gid, proactive)?;
+    let task = state.spawn_task(gid, TaskKind::Reply, request);
+
+    let room = state
+        .rooms
+        .get_mut(&gid)
+        .context("received update for group im not in")?;
+    room.pending_generation = None;
+    // the reply it was generating is outdated
+    if let Some(outdated) = room.ollama_api_task.replace(task) {
+        outdated.abort();
     }
+    room.generation_started = Some((Instant::now(), room.received_messages));
+    room.last_generation_start = Some(Instant::now());
+
+    let event = if proactive {
+        "proactive generation started"
+    } else {
+        "generation started"
+    };
+    state.log_activity(gid, event.to_owned());
 
     Ok(())
 }
@@ -113,7 +699,8 @@ pub async fn handle_mc_update(state: &mut State, update: Update) -> anyhow::Resu
 fn send_ollama_request(
     state: &State,
     gid: u32,
-) -> anyhow::Result<JoinHandle<anyhow::Result<String>>> {
+    proactive: bool,
+) -> anyhow::Result<impl Future<Output = anyhow::Result<String>>> {
     macro_rules! room {
         () => {
             state
@@ -124,39 +711,103 @@ fn send_ollama_request(
     }
 
     // Prepare the ollama API request
-    let system_prompt = state
+    let room = room!();
+    let segments = match &room.system_prompt_override {
+        Some(prompt) => std::slice::from_ref(prompt),
+        None => room.settings.system_prompt.as_slice(),
+    };
+    let segments: Vec<String> = segments
+        .iter()
+        .map(|segment| fill_template(segment, room, &state.config.ollama))
+        .collect();
+    // the messages that {memories} is filled into, once the relevant ones are picked
+    let mut templated: Vec<usize> = Vec::new();
+    let segments = if state.config.ollama.merge_system_prompt {
+        vec![segments.join("\n\n")]
+    } else {
+        segments
+    };
+    templated.extend(0..segments.len());
+    let mut messages: Vec<_> = segments
+        .into_iter()
+        .map(|content| OllamaRequestMessage {
+            role: "system".to_string(),
+            content,
+            images: Vec::new(),
+        })
+        .collect();
+
+    // a fresh room - nothing remembered and nothing said by me yet
+    let cold_start =
+        room!().memories.is_empty() && !room!().message_history.iter().any(|msg| msg.was_me);
+    if let Some(cold_start_prompt) = state
         .config
         .ollama
-        .system_prompt
-        .replace("{mention_name}", &state.config.ollama.mention_name)
-        .replace(
-            "{memories}",
-            &room!()
-                .memories
-                .iter()
-                .map(|m| format!("- {m}\n"))
-                .collect::<String>(),
-        );
-    let mut messages: Vec<_> = vec![
-        OllamaRequestMessage {
+        .cold_start_prompt
+        .as_ref()
+        .filter(|_| cold_start)
+    {
+        templated.push(messages.len());
+        messages.push(OllamaRequestMessage {
             role: "system".to_string(),
-            content: system_prompt,
+            content: fill_template(cold_start_prompt, room!(), &state.config.ollama),
             images: Vec::new(),
-        },
-        OllamaRequestMessage {
-            role: "assistant".to_string(),
-            content: format!("Hello everyone! I'm back! Ready to be racist and fun with you! :)"),
-            images: Vec::new(),
-        },
-    ];
+        });
+    }
+
+    // some model families reject the primer, and all of them may reject two assistant
+    // messages in a row, which is what we'd send if the history starts with my own message
+    let primer_enabled = !state
+        .config
+        .ollama
+        .no_primer_models
+        .contains(&room!().settings.model)
+        && !state
+            .config
+            .ollama
+            .no_primer_groups
+            .contains(&room!().room_name);
+    let history_starts_with_me = room!()
+        .message_history
+        .front()
+        .map(|msg| msg.was_me)
+        .unwrap_or(false);
+    // with enough real history the model is steered by it already, so save the context
+    let history_len = room!().message_history.len();
+    let history_is_long = state
+        .config
+        .ollama
+        .primer_max_history
+        .map(|max| history_len >= max)
+        .unwrap_or(false);
+    if let Some(primer) = state
+        .config
+        .ollama
+        .assistant_primer
+        .as_ref()
+        .filter(|_| primer_enabled)
+    {
+        if history_starts_with_me {
+            debug!("Skipping the assistant primer to avoid consecutive assistant messages");
+        } else if history_is_long {
+            debug!("Skipping the assistant primer, the history is long enough");
+        } else {
+            templated.push(messages.len());
+            messages.push(OllamaRequestMessage {
+                role: "assistant".to_string(),
+                content: fill_template(primer, room!(), &state.config.ollama),
+                images: Vec::new(),
+            });
+        }
+    }
 
     // add the real message history
-    // we only keep the last image, so find out which message has it
+    // only the images of the last message that has any are sent, so find out which one it is
     let last_image_idx = room!()
         .message_history
         .iter()
         .rev()
-        .position(|msg| msg.image.is_some());
+        .position(|msg| !msg.images.is_empty());
     messages.extend(
         room!()
             .message_history
@@ -165,51 +816,296 @@ fn send_ollama_request(
             .enumerate()
             .map(|(i, msg)| OllamaRequestMessage {
                 role: if msg.was_me { "assistant" } else { "user" }.to_string(),
-                content: msg.format(),
+                // prefixed with a timestamp as configured by timestamp-format
+                content: msg.format(&state.config.ollama),
                 images: if last_image_idx.map(|idx| idx == i).unwrap_or(false) {
-                    vec![msg.image.clone().unwrap()]
+                    msg.images.clone()
                 } else {
                     vec![]
                 },
             })
             .rev(),
     );
-    let body = OllamaRequest {
-        model: state.config.ollama.model.clone(),
-        messages,
-        stream: false,
-        keep_alive: "30s".to_string(), // how long to keep the model loaded for
-        options: OllamaRequestOptions {
-            temperature: state.config.ollama.temperature,
-            top_k: state.config.ollama.top_k,
-        },
+
+    // re-state the key instructions right before generating, so they're not forgotten in long conversations
+    if let Some(reminder_prompt) = &state.config.ollama.reminder_prompt {
+        templated.push(messages.len());
+        messages.push(OllamaRequestMessage {
+            role: state.config.ollama.reminder_role.as_str().to_string(),
+            content: fill_template(reminder_prompt, room!(), &state.config.ollama),
+            images: Vec::new(),
+        });
+    }
+
+    if proactive {
+        templated.push(messages.len());
+        messages.push(OllamaRequestMessage {
+            role: "system".to_string(),
+            content: fill_template(
+                &state.config.ollama.proactive_prompt,
+                room!(),
+                &state.config.ollama,
+            ),
+            images: Vec::new(),
+        });
+    }
+
+    let memory_lines = memory_lines(room!(), &state.config.ollama);
+    // picked by what the last person said
+    let query = room!()
+        .message_history
+        .iter()
+        .rev()
+        .find(|msg| !msg.was_me)
+        .map(|msg| msg.message.clone());
+    let retrieval = state
+        .config
+        .ollama
+        .memory_retrieval_top_k
+        .filter(|top_k| room.memories.len() > *top_k)
+        .zip(query)
+        .map(|(top_k, query)| {
+            let retrieval = Retrieval {
+                ollama: state.ollama.clone(),
+                cache: state.memory_embeddings.clone(),
+                model: state.config.ollama.embedding_model.clone(),
+                top_k,
+            };
+            (retrieval, query, room.memories.clone())
+        });
+
+    let group = room!().room_name.clone();
+    let audit = state.audit.as_ref().map(|audit| (audit.sender(), group));
+    let ollama = state.ollama.clone();
+    let body = request_body(state, room!());
+
+    let min_reply_delay_ms = state.config.ollama.min_reply_delay_ms;
+    let typing_ms_per_char = state.config.ollama.typing_ms_per_char;
+
+    // to be spawned as a task sending a request to the ollama api
+    let task = async move {
+        let started = Instant::now();
+
+        // embedding takes requests of its own, so it's done here and not in the main loop
+        let relevant = match &retrieval {
+            Some((retrieval, query, memories)) => retrieval.select(query, memories).await,
+            None => None,
+        };
+        let memories: String = memory_lines
+            .iter()
+            .filter(|(memory, _line)| {
+                relevant
+                    .as_ref()
+                    .is_none_or(|relevant| relevant.contains(memory))
+            })
+            .map(|(_memory, line)| format!("{line}\n"))
+            .collect();
+        for idx in templated {
+            messages[idx].content = messages[idx].content.replace("{memories}", &memories);
+        }
+
+        let logged = audit.map(|audit| (audit, messages.clone()));
+        let content = ollama.generate(body(messages)).await?;
+
+        if let Some(((audit, group), messages)) = logged {
+            audit.log(group, messages, content.clone()).await;
+        }
+
+        // hold the reply back so it doesn't feel instant. the time spent generating counts
+        // towards the delay, and since this is in the task, other rooms aren't blocked
+        let reply_delay = Duration::from_millis(
+            min_reply_delay_ms + typing_ms_per_char * content.chars().count() as u64,
+        );
+        if let Some(remaining) = reply_delay.checked_sub(started.elapsed()) {
+            sleep(remaining).await;
+        }
+
+        Ok(content)
     };
 
-    let mut url = state.config.ollama.base_url.clone();
-    url.set_path("api/chat");
+    Ok(task)
+}
+
+/// Substitutes the placeholders in a prompt template, except for {memories}
+fn fill_template(template: &str, room: &RoomState, config: &Ollama) -> String {
+    template
+        .replace("{mention_name}", &room.mention_name)
+        .replace(
+            "{current_time}",
+            &Utc::now()
+                .with_timezone(&config.timezone)
+                .format(&config.current_time_format)
+                .to_string(),
+        )
+}
 
-    let auth_user = state.config.ollama.basic_auth_user.clone();
-    let auth_password = state.config.ollama.basic_auth_password.clone();
+/// Each memory with how it's listed in {memories}
+fn memory_lines(room: &RoomState, config: &Ollama) -> Vec<(String, String)> {
+    room.memories
+        .iter()
+        .enumerate()
+        .map(|(i, memory)| {
+            // {index} matches the numbering of /mems
+            let line = config
+                .memory_template
+                .replace("{index}", &i.to_string())
+                .replace("{text}", memory);
+            (memory.clone(), line)
+        })
+        .collect()
+}
 
-    let reqw = state.reqw.clone();
+fn send_consolidation_request(
+    state: &State,
+    gid: u32,
+) -> anyhow::Result<impl Future<Output = anyhow::Result<String>>> {
+    let room = state
+        .rooms
+        .get(&gid)
+        .context("received update for group im not in")?;
+    let memories = room
+        .memories
+        .iter()
+        .map(|m| format!("- {m}\n"))
+        .collect::<String>();
+
+    let messages = vec![
+        OllamaRequestMessage {
+            role: "system".to_string(),
+            content: CONSOLIDATION_PROMPT.to_string(),
+            images: Vec::new(),
+        },
+        OllamaRequestMessage {
+            role: "user".to_string(),
+            content: memories,
+            images: Vec::new(),
+        },
+    ];
 
-    // spawn a task to send a request to the ollama api
-    let join_handle = tokio::spawn(async move {
-        let response = reqw
-            .post(url)
-            .basic_auth(&auth_user, Some(&auth_password))
-            .json(&body)
-            .send()
-            .await;
+    Ok(chat_request(state, room, messages))
+}
 
-        let response = response?.error_for_status()?;
+fn send_relearn_request(
+    state: &State,
+    gid: u32,
+) -> anyhow::Result<impl Future<Output = anyhow::Result<String>>> {
+    let room = state
+        .rooms
+        .get(&gid)
+        .context("received update for group im not in")?;
+    let transcript = room
+        .message_history
+        .iter()
+        .map(|msg| {
+            msg.format_transcript(
+                &state.config.multichat.user_name,
+                state.config.ollama.timezone,
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
 
-        let response = response.json::<OllamaResponse>().await?;
+    let messages = vec![
+        OllamaRequestMessage {
+            role: "system".to_string(),
+            content: RELEARN_PROMPT.to_string(),
+            images: Vec::new(),
+        },
+        OllamaRequestMessage {
+            role: "user".to_string(),
+            content: transcript,
+            images: Vec::new(),
+        },
+    ];
 
-        Ok(response.message.content)
-    });
+    Ok(chat_request(state, room, messages))
+}
 
-    Ok(join_handle)
+/// Prepares a request to ollama, to be awaited in a spawned task
+fn chat_request(
+    state: &State,
+    room: &RoomState,
+    messages: Vec<OllamaRequestMessage>,
+) -> ResponseFuture {
+    state.ollama.generate(request_body(state, room)(messages))
+}
+
+/// Builds the body of a request to ollama from the messages, which may be finished later in a task
+fn request_body(
+    state: &State,
+    room: &RoomState,
+) -> impl FnOnce(Vec<OllamaRequestMessage>) -> RequestBody + Send + 'static {
+    let model = room.settings.model.clone();
+    let keep_alive = room.settings.keep_alive.clone();
+    let mut options = OllamaRequestOptions::new(&state.config.ollama);
+    options.temperature = room.settings.temperature;
+    options.top_k = room.settings.top_k;
+    let chat_template = state.config.ollama.chat_template;
+    let endpoint = state.config.ollama.endpoint;
+    let stream = state.config.ollama.stream;
+    let mention_name = room.mention_name.clone();
+
+    move |messages| {
+        let prompt = match chat_template {
+            // the backend doesn't template the conversation for us
+            Some(template) => Some(chat_template::render(template, &messages)),
+            None if endpoint == Endpoint::Generate => {
+                Some(chat_template::flatten(&messages, &mention_name))
+            }
+            None => None,
+        };
+        match prompt {
+            None => RequestBody::Chat(OllamaRequest {
+                model,
+                messages,
+                stream,
+                keep_alive,
+                options,
+            }),
+            Some(prompt) => RequestBody::Generate(GenerateRequest {
+                model,
+                prompt,
+                images: messages.into_iter().flat_map(|msg| msg.images).collect(),
+                raw: true,
+                stream,
+                keep_alive,
+                options,
+            }),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum ImageKind {
+    Jpeg,
+    Png,
+    Gif,
+    Webp,
+}
+
+/// Recognizes the image formats ollama accepts by their magic bytes
+fn detect_image_kind(bytes: &[u8]) -> Option<ImageKind> {
+    match bytes {
+        [0xFF, 0xD8, 0xFF, ..] => Some(ImageKind::Jpeg),
+        [0x89, b'P', b'N', b'G', ..] => Some(ImageKind::Png),
+        [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Some(ImageKind::Gif),
+        // RIFF is also used by audio and video, the fourcc says what's inside
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some(ImageKind::Webp),
+        _ => None,
+    }
+}
+
+fn is_admin(state: &State, gid: u32, uid: u32) -> bool {
+    let Some(admin_users) = &state.config.multichat.admin_users else {
+        // no admins configured, so everyone is allowed
+        return true;
+    };
+
+    state
+        .rooms
+        .get(&gid)
+        .and_then(|room| room.usernames.get(&uid))
+        .is_some_and(|username| admin_users.contains(username))
 }
 
 fn is_substring_isolated(s: &str, substr: &str) -> bool {
@@ -239,3 +1135,89 @@ fn is_substring_isolated(s: &str, substr: &str) -> bool {
         false
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ollama_client::TestClient;
+    use crate::tests::test_state;
+    use crate::{REPL_GID, REPL_USER_UID};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn commands_mentioning_the_bot_are_commands() {
+        let client = Arc::new(TestClient::new("hi"));
+        let (mut state, dir) = test_state("command-test", client.clone()).await;
+
+        let msg = "/stats @ollama";
+        assert!(is_substring_isolated(msg, "ollama"));
+        handle_message(&mut state, REPL_GID, REPL_USER_UID, msg, Vec::new())
+            .await
+            .unwrap();
+
+        let room = &state.rooms[&REPL_GID];
+        assert!(room.ollama_api_task.is_none() && room.pending_generation.is_none());
+        assert!(room.message_history.is_empty());
+        assert!(client.requests.lock().unwrap().is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn unknown_slash_messages_are_chat() {
+        let client = Arc::new(TestClient::new("hi"));
+        let (mut state, dir) = test_state("slash-chat-test", client).await;
+
+        for msg in ["/me waves at ollama", "/r/rust is great, ollama?"] {
+            handle_message(&mut state, REPL_GID, REPL_USER_UID, msg, Vec::new())
+                .await
+                .unwrap();
+        }
+
+        let room = &state.rooms[&REPL_GID];
+        assert_eq!(room.message_history.len(), 2);
+        assert!(room.ollama_api_task.is_some() || room.pending_generation.is_some());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(not(feature = "downscale"))]
+    #[tokio::test]
+    async fn failed_downscaling_gives_the_original_back() {
+        let config = include_str!("../example/config.toml");
+        let mut config = toml::from_str::<crate::config::Config>(config).unwrap();
+        config.ollama.max_image_bytes = Some(4);
+        config.ollama.downscale_images_to = Some(16);
+
+        let image = b"\x89PNG\r\n\x1a\n".to_vec();
+        let (_e, original) = fit_image_size(&config.ollama, image.clone())
+            .await
+            .unwrap_err();
+        assert_eq!(original, image);
+
+        config.ollama.downscale_images_to = None;
+        assert_eq!(fit_image_size(&config.ollama, image).await.unwrap(), None);
+    }
+
+    #[test]
+    fn detects_image_kinds() {
+        assert_eq!(detect_image_kind(b"GIF89a\x01\x00"), Some(ImageKind::Gif));
+        assert_eq!(
+            detect_image_kind(b"RIFF\x24\x00\x00\x00WEBPVP8 "),
+            Some(ImageKind::Webp)
+        );
+        assert_eq!(detect_image_kind(b"RIFF\x24\x00\x00\x00WAVEfmt "), None);
+        assert_eq!(detect_image_kind(b"\x89PNG\r\n"), Some(ImageKind::Png));
+
+        // truncated headers
+        for bytes in [
+            &b""[..],
+            b"\xFF\xD8",
+            b"GIF8",
+            b"RIFF",
+            b"RIFF\x24\x00\x00\x00WEB",
+        ] {
+            assert_eq!(detect_image_kind(bytes), None);
+        }
+    }
+}
@@ -1,56 +1,472 @@
-use crate::{room_state::Message, State};
-use std::time::Duration;
+use crate::{
+    config::{BlockAction, Ollama},
+    error::Error,
+    room_state::Message,
+    State,
+};
+use chrono::Utc;
+use regex::Regex;
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
 use tokio::time::sleep;
-use tracing::error;
+use tracing::{error, info, warn};
 
 pub async fn handle_ollama_gen(
     state: &mut State,
     gid: u32,
     res: anyhow::Result<String>,
-) -> anyhow::Result<()> {
+) -> Result<(), Error> {
     // finished generating response to some chatroom
-    state.rooms.get_mut(&gid).unwrap().ollama_api_task = None;
-    let my_uid = state.rooms[&gid].my_uid;
+    let room = state.rooms.get_mut(&gid).unwrap();
+    room.ollama_api_task = None;
+    let generation_started = room.generation_started.take();
+    let received_messages = room.received_messages;
+    let my_uid = room.my_uid;
+    let mention_name = room.mention_name.clone();
+    let allow_memory_creation = room.allow_memory_creation;
 
     let response = match res {
         Ok(r) => r,
         Err(e) => {
             error!("Failed ollama request. {e:?}");
+            let room = state.rooms.get_mut(&gid).unwrap();
+            room.last_error = Some((Utc::now(), format!("{e:#}")));
+            room.consecutive_failures += 1;
+            if let Some(base) = state.config.ollama.error_cooldown_secs {
+                let doublings = (room.consecutive_failures - 1).min(16);
+                let secs = base
+                    .saturating_mul(1 << doublings)
+                    .min(state.config.ollama.error_cooldown_max_secs);
+                room.error_cooldown = Some(Instant::now() + Duration::from_secs(secs));
+                room.cooldown_notice_sent = false;
+            }
+            state.log_activity(gid, format!("generation failed: {e}"));
             state
-                .mc_client
-                .send_message(gid, my_uid, &format!("Failed ollama request. {e}"), &[])
+                .send_message(gid, my_uid, &format!("Failed ollama request. {e}"))
                 .await?;
             return Ok(());
         }
     };
 
-    let response = clean_generated_msg(&response, &state.config.ollama.mention_name);
+    let room = state.rooms.get_mut(&gid).unwrap();
+    room.consecutive_failures = 0;
+    room.error_cooldown = None;
 
-    state.push_message(gid, Message::new(response, true, None));
+    // drop the response if the conversation has moved on since it was requested
+    if let Some((started, received_at_start)) = generation_started {
+        let new_messages = received_messages - received_at_start;
+        let too_many_messages = state
+            .config
+            .ollama
+            .stale_after_messages
+            .map(|n| new_messages > n)
+            .unwrap_or(false);
+        let too_old = state
+            .config
+            .ollama
+            .stale_after_secs
+            .map(|secs| started.elapsed() > Duration::from_secs(secs))
+            .unwrap_or(false);
 
-    // check if new memory created
-    if let Some(memory) = extract_between_tags(response, "<MEMORY>", "</MEMORY>") {
-        state.add_memory(gid, memory.to_owned()).await?;
+        if too_many_messages || too_old {
+            info!(
+                "Dropping stale response in group {gid} ({new_messages} new messages, started {:?} ago)",
+                started.elapsed()
+            );
+            return Ok(());
+        }
     }
 
-    // reply with the message contents
-    for msg in response.split("\n\n") {
-        let cleaned_msg = clean_generated_msg(&msg, &state.config.ollama.mention_name);
-        if cleaned_msg.is_empty() {
-            continue;
+    // reasoning models think out loud before answering
+    let response = if state.config.ollama.strip_think_tags {
+        remove_between_tags(&response, "<think>", "</think>")
+    } else {
+        response
+    };
+    let response = clean_generated_msg(&response, &mention_name);
+
+    state.rooms.get_mut(&gid).unwrap().last_response = Some(Utc::now());
+
+    // check if new memories created
+    let open_tag = state.config.ollama.memory_open_tag.clone();
+    let close_tag = state.config.ollama.memory_close_tag.clone();
+    let memories: Vec<String> = extract_between_tags(response, &open_tag, &close_tag)
+        .into_iter()
+        .map(str::trim)
+        .filter(|memory| !memory.is_empty())
+        .map(str::to_owned)
+        .collect();
+    if !memories.is_empty() {
+        if allow_memory_creation {
+            state.add_memories(gid, memories).await?;
+        } else {
+            info!("Not saving memories in group {gid}, memory creation is disabled there");
         }
+    }
 
+    // before splitting, since a memory can span paragraphs
+    let stripped = strip_memory_tags(
+        response,
+        &open_tag,
+        &close_tag,
+        state.config.ollama.memory_leadin_max_chars,
+    );
+    let paragraphs: Vec<&str> = if state.config.ollama.split_on_double_newline {
+        stripped.split("\n\n").collect()
+    } else {
+        vec![&stripped]
+    };
+    let messages: Vec<String> = paragraphs
+        .into_iter()
+        .map(|msg| clean_generated_msg(msg, &mention_name).to_owned())
+        .map(|msg| {
+            strip_self_references(
+                &msg,
+                &state.config.ollama.self_reference_patterns,
+                &mention_name,
+            )
+        })
+        .map(|msg| match &state.config.ollama.self_mention_replacement {
+            Some(replacement) => replace_self_mentions(&msg, &mention_name, replacement),
+            None => msg,
+        })
+        .filter(|msg| !msg.is_empty())
+        .map(|msg| {
+            if state.config.ollama.strip_markdown {
+                strip_markdown(&msg)
+            } else {
+                msg
+            }
+        })
+        .collect();
+    let (messages, any_blocked) = apply_blocklist(messages, &state.config.ollama);
+    // what was blocked mustn't be kept for the model to repeat, so the history gets what's sent
+    let history_text = if any_blocked {
+        messages.join("\n\n")
+    } else {
+        response.to_owned()
+    };
+    if !history_text.is_empty() {
+        state.push_message(gid, Message::new(&history_text, true, None, Vec::new()));
+    }
+    let messages: Vec<String> = match state.config.ollama.max_message_chars {
+        Some(max_chars) => messages
+            .iter()
+            .flat_map(|msg| split_long_message(msg, max_chars))
+            .collect(),
+        None => messages,
+    };
+
+    // reply with the message contents
+    for msg in messages {
         sleep(Duration::from_millis(500)).await;
 
-        state
-            .mc_client
-            .send_message(gid, my_uid, cleaned_msg, &[])
-            .await?;
+        state.send_message(gid, my_uid, &msg).await?;
     }
 
     Ok(())
 }
 
+pub async fn handle_consolidation(
+    state: &mut State,
+    gid: u32,
+    res: anyhow::Result<String>,
+) -> Result<(), Error> {
+    let room = state.rooms.get_mut(&gid).unwrap();
+    room.consolidation_task = None;
+    let my_uid = room.my_uid;
+
+    let reply = match res {
+        Ok(response) => {
+            let memories: Vec<String> = response
+                .lines()
+                .map(|line| line.trim().trim_start_matches(['-', '*']).trim())
+                .filter(|line| !line.is_empty())
+                .map(str::to_owned)
+                .collect();
+
+            if memories.is_empty() {
+                "consolidation returned no memories, keeping the current ones".to_string()
+            } else {
+                let formatted_mems = memories
+                    .iter()
+                    .enumerate()
+                    .map(|(i, m)| format!("{i} - {m}\n"))
+                    .collect::<String>();
+                let reply = format!(
+                    "{formatted_mems}\nsend /consolidate confirm to replace the {} current memories with these {}, or /consolidate cancel",
+                    room.memories.len(),
+                    memories.len()
+                );
+                room.pending_consolidation = Some(memories);
+
+                reply
+            }
+        }
+        Err(e) => {
+            error!("Failed to consolidate memories. {e:?}");
+            format!("Failed to consolidate memories. {e}")
+        }
+    };
+
+    state.send_message(gid, my_uid, &reply).await?;
+
+    Ok(())
+}
+
+pub async fn handle_relearn(
+    state: &mut State,
+    gid: u32,
+    res: anyhow::Result<String>,
+) -> Result<(), Error> {
+    let max_memories = state.config.ollama.max_relearned_memories;
+    let room = state.rooms.get_mut(&gid).unwrap();
+    room.relearn_task = None;
+    let my_uid = room.my_uid;
+
+    let reply = match res {
+        Ok(response) => {
+            let memories: Vec<String> = response
+                .lines()
+                .map(|line| line.trim().trim_start_matches(['-', '*']).trim())
+                .filter(|line| !line.is_empty())
+                .take(max_memories)
+                .map(str::to_owned)
+                .collect();
+
+            if memories.is_empty() {
+                "found nothing worth remembering".to_string()
+            } else {
+                let formatted_mems = memories
+                    .iter()
+                    .map(|m| format!("- {m}\n"))
+                    .collect::<String>();
+                let reply = format!(
+                    "{formatted_mems}\nsend /relearn confirm to add these {} memories, or /relearn cancel",
+                    memories.len()
+                );
+                room.pending_relearn = Some(memories);
+
+                reply
+            }
+        }
+        Err(e) => {
+            error!("Failed to relearn memories. {e:?}");
+            format!("Failed to relearn memories. {e}")
+        }
+    };
+
+    state.send_message(gid, my_uid, &reply).await?;
+
+    Ok(())
+}
+
+pub async fn handle_model_switch(
+    state: &mut State,
+    gid: u32,
+    res: anyhow::Result<String>,
+) -> Result<(), Error> {
+    let room = state.rooms.get_mut(&gid).unwrap();
+    room.model_switch_task = None;
+    let my_uid = room.my_uid;
+
+    let reply = match res {
+        Ok(model) => {
+            room.settings.model = model;
+            "model switched".to_string()
+        }
+        Err(e) => format!("{e:#}"),
+    };
+
+    state.send_message(gid, my_uid, &reply).await?;
+
+    Ok(())
+}
+
+/// Applies the output blocklist, returning the messages to send and whether anything was blocked
+fn apply_blocklist(messages: Vec<String>, config: &Ollama) -> (Vec<String>, bool) {
+    let blocklist = || {
+        config
+            .output_blocklist
+            .iter()
+            .chain(&config.output_blocked_phrases)
+    };
+    let is_blocked = |msg: &str| blocklist().any(|re| re.is_match(msg));
+
+    let mut any_blocked = false;
+    for msg in messages.iter().filter(|msg| is_blocked(msg.as_str())) {
+        warn!("Blocked generated message {msg:?}");
+        any_blocked = true;
+    }
+    if !any_blocked {
+        return (messages, false);
+    }
+
+    let messages = match config.output_block_action {
+        BlockAction::Redact => messages
+            .into_iter()
+            .map(|msg| blocklist().fold(msg, |msg, re| re.replace_all(&msg, "***").into_owned()))
+            .collect(),
+        BlockAction::Skip => messages
+            .into_iter()
+            .filter(|msg| !is_blocked(msg.as_str()))
+            .collect(),
+        BlockAction::Replace => vec![config.output_block_message.clone()],
+    };
+
+    (messages, true)
+}
+
+/// Splits a message into parts of at most `max_chars` chars, at whitespace where possible
+pub fn split_long_message(msg: &str, max_chars: usize) -> Vec<String> {
+    let max_chars = max_chars.max(1);
+
+    let mut parts = Vec::new();
+    let mut rest = msg.trim();
+    while let Some((limit, _)) = rest.char_indices().nth(max_chars) {
+        let cut = if rest[limit..].starts_with(char::is_whitespace) {
+            limit
+        } else {
+            rest[..limit]
+                .rfind(char::is_whitespace)
+                .filter(|&i| i > 0)
+                .unwrap_or(limit)
+        };
+
+        parts.push(rest[..cut].trim_end().to_owned());
+        rest = rest[cut..].trim_start();
+    }
+    if !rest.is_empty() {
+        parts.push(rest.to_owned());
+    }
+
+    parts
+}
+
+/// Removes the markdown syntax that shows up as clutter when rendered literally
+fn strip_markdown(msg: &str) -> String {
+    msg.lines()
+        .map(|line| {
+            let header = line.trim_start_matches('#');
+            let line = match header.strip_prefix(' ') {
+                Some(rest) if header.len() < line.len() => rest,
+                _ => line,
+            };
+
+            line.replace("**", "").replace("__", "").replace('`', "")
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Removes the memory blocks from a message about to be sent, even ones spanning lines. What's
+/// left of a line that only introduced the memory ("I'll remember that:") goes too, if it's at
+/// most `max_leadin_chars` long
+fn strip_memory_tags(
+    msg: &str,
+    open_tag: &str,
+    close_tag: &str,
+    max_leadin_chars: usize,
+) -> String {
+    let mut stripped = String::new();
+    // the lines of what's left that had a memory in them
+    let mut had_memory = HashSet::new();
+    let mut rest = msg;
+    while let Some(start) = rest.find(open_tag) {
+        stripped.push_str(&rest[..start]);
+        had_memory.insert(stripped.matches('\n').count());
+
+        let after_open = &rest[start + open_tag.len()..];
+        rest = match after_open.find(close_tag) {
+            Some(end) => &after_open[end + close_tag.len()..],
+            // not closed, so it goes on to the end
+            None => "",
+        };
+    }
+    stripped.push_str(rest);
+
+    stripped
+        .lines()
+        .enumerate()
+        .filter_map(|(i, line)| {
+            if !had_memory.contains(&i) {
+                return Some(line.to_owned());
+            }
+
+            let leftover = line.trim();
+            if leftover.is_empty() || leftover.chars().count() <= max_leadin_chars {
+                None
+            } else {
+                Some(leftover.to_owned())
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Removes the bot talking about itself in third person, `patterns` can contain {mention_name}.
+/// If the message started with one, the rest is capitalized
+fn strip_self_references(msg: &str, patterns: &[String], mention_name: &str) -> String {
+    let mut msg = msg.to_owned();
+    for pattern in patterns {
+        let pattern = pattern.replace("{mention_name}", mention_name);
+        if pattern.trim().is_empty() {
+            continue;
+        }
+        let re = Regex::new(&format!("(?i){}", regex::escape(&pattern))).unwrap();
+
+        let starts_with_it = re.find(&msg).is_some_and(|m| m.start() == 0);
+        msg = re.replace_all(&msg, "").into_owned();
+        if starts_with_it {
+            let mut chars = msg.chars();
+            if let Some(first) = chars.next() {
+                msg = first.to_uppercase().chain(chars).collect();
+            }
+        }
+    }
+
+    msg.trim().to_owned()
+}
+
+/// Replaces the mention name wherever it's a word of its own, ignoring case
+fn replace_self_mentions(msg: &str, mention_name: &str, replacement: &str) -> String {
+    if mention_name.is_empty() {
+        return msg.to_owned();
+    }
+    let re = Regex::new(&format!("(?i){}", regex::escape(mention_name))).unwrap();
+
+    let mut result = String::new();
+    let mut last = 0;
+    for m in re.find_iter(msg) {
+        let before = msg[..m.start()].chars().next_back();
+        let after = msg[m.end()..].chars().next();
+        if before.is_some_and(char::is_alphabetic) || after.is_some_and(char::is_alphabetic) {
+            continue;
+        }
+
+        result.push_str(&msg[last..m.start()]);
+        result.push_str(replacement);
+        last = m.end();
+
+        if replacement.is_empty() {
+            // removing a word leaves a doubled space, or one before punctuation, behind
+            let rest = &msg[last..];
+            let rest_trimmed = rest.trim_start_matches(' ');
+            if result.is_empty() || result.ends_with([' ', '\n']) {
+                last += rest.len() - rest_trimmed.len();
+            }
+            if rest_trimmed.is_empty()
+                || rest_trimmed.starts_with([',', '.', '!', '?', ':', ';', '\n'])
+            {
+                result.truncate(result.trim_end_matches(' ').len());
+            }
+        }
+    }
+    result.push_str(&msg[last..]);
+
+    result
+}
+
 fn clean_generated_msg<'a, 'b>(msg: &'a str, llm_name: &'b str) -> &'a str {
     // Trim
     // Remove quotes
@@ -63,26 +479,49 @@ fn clean_generated_msg<'a, 'b>(msg: &'a str, llm_name: &'b str) -> &'a str {
         .trim()
 }
 
-fn extract_between_tags<'a>(
-    text: &'a str,
-    start_tag: &'a str,
-    end_tag: &'a str,
-) -> Option<&'a str> {
-    if let Some(start_idx) = text.find(start_tag) {
+/// The contents of every closed tagged block
+fn extract_between_tags<'a>(text: &'a str, start_tag: &str, end_tag: &str) -> Vec<&'a str> {
+    let mut blocks = Vec::new();
+    let mut rest = text;
+    while let Some(start_idx) = rest.find(start_tag) {
         let start = start_idx + start_tag.len();
-        if let Some(end_idx) = text[start..].find(end_tag) {
-            return Some(&text[start..start + end_idx]);
-        }
+        let Some(end_idx) = rest[start..].find(end_tag) else {
+            break;
+        };
+        blocks.push(&rest[start..start + end_idx]);
+        rest = &rest[start + end_idx + end_tag.len()..];
     }
-    None
+
+    blocks
+}
+
+/// Removes every tagged block, tags included. A block that isn't closed goes on to the end
+fn remove_between_tags(text: &str, start_tag: &str, end_tag: &str) -> String {
+    let mut rest = text.to_owned();
+    while let Some(start) = rest.find(start_tag) {
+        let end = rest[start..]
+            .find(end_tag)
+            .map(|end| start + end + end_tag.len())
+            .unwrap_or(rest.len());
+        rest.replace_range(start..end, "");
+    }
+
+    rest
 }
 
 fn remove_prefix_case_insensitive<'a, 'b>(s: &'a str, prefix: &'b str) -> &'a str {
-    if s.to_lowercase().starts_with(&prefix.to_lowercase()) {
-        &s[prefix.len()..]
-    } else {
-        s
+    // compare char by char, since lowercasing can change the byte length,
+    // and the cut has to land on a char boundary of `s`
+    let mut s_chars = s.char_indices();
+    for prefix_char in prefix.chars() {
+        match s_chars.next() {
+            Some((_, c)) if c.to_lowercase().eq(prefix_char.to_lowercase()) => {}
+            _ => return s,
+        }
     }
+
+    let end = s_chars.next().map(|(i, _)| i).unwrap_or(s.len());
+    &s[end..]
 }
 
 fn remove_quotes(s: &str) -> &str {
@@ -92,3 +531,216 @@ fn remove_quotes(s: &str) -> &str {
         s
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ollama_client::TestClient;
+    use crate::tests::test_state;
+    use crate::REPL_GID;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn blocked_text_stays_out_of_the_history() {
+        let (mut state, dir) = test_state("blocklist-test", Arc::new(TestClient::new(""))).await;
+        state.config.ollama.output_blocklist = vec![Regex::new(r"(?i)rm -rf /").unwrap()];
+
+        let reply = "Just run RM -rf / and it's fixed".to_owned();
+        handle_ollama_gen(&mut state, REPL_GID, Ok(reply))
+            .await
+            .unwrap();
+
+        let room = &state.rooms[&REPL_GID];
+        assert_eq!(
+            room.message_history.back().unwrap().message,
+            "Just run *** and it's fixed"
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn removes_prefix_case_insensitive() {
+        assert_eq!(
+            remove_prefix_case_insensitive("Ollama: hi", "ollama"),
+            ": hi"
+        );
+        assert_eq!(
+            remove_prefix_case_insensitive("hi ollama", "ollama"),
+            "hi ollama"
+        );
+        assert_eq!(remove_prefix_case_insensitive("olla", "ollama"), "olla");
+        assert_eq!(remove_prefix_case_insensitive("ollama", "ollama"), "");
+    }
+
+    #[test]
+    fn removes_prefix_on_char_boundaries() {
+        assert_eq!(remove_prefix_case_insensitive("🤖Bot: 👋", "🤖bot"), ": 👋");
+        assert_eq!(
+            remove_prefix_case_insensitive("機器人：你好", "機器人"),
+            "：你好"
+        );
+        assert_eq!(remove_prefix_case_insensitive("機器", "機器人"), "機器");
+        assert_eq!(remove_prefix_case_insensitive("É", "ée"), "É");
+        // lowercases to a longer string, which used to make the cut land mid-char
+        assert_eq!(remove_prefix_case_insensitive("İa", "i"), "İa");
+    }
+
+    #[test]
+    fn cleans_multibyte_messages() {
+        assert_eq!(
+            clean_generated_msg("\"Ollama 你好 🎉\"", "ollama"),
+            "你好 🎉"
+        );
+        assert_eq!(clean_generated_msg(" 🎉 ", "ollama"), "🎉");
+    }
+
+    #[test]
+    fn strip_markdown_removes_emphasis_and_headers() {
+        assert_eq!(
+            strip_markdown("## Title\nsome **bold** and `code`, #hashtag"),
+            "Title\nsome bold and code, #hashtag"
+        );
+    }
+
+    #[test]
+    fn strips_self_references() {
+        let patterns = vec!["As {mention_name}, ".to_owned()];
+        assert_eq!(
+            strip_self_references("As Bot, i think tea is great.", &patterns, "Bot"),
+            "I think tea is great."
+        );
+        assert_eq!(
+            strip_self_references("Well, as bot, I'd say no.", &patterns, "Bot"),
+            "Well, I'd say no."
+        );
+        // the name on its own is left alone
+        assert_eq!(
+            strip_self_references("Bot is my name, and Bot.js is not", &patterns, "Bot"),
+            "Bot is my name, and Bot.js is not"
+        );
+        assert_eq!(
+            strip_self_references("As Bot, i think", &[], "Bot"),
+            "As Bot, i think"
+        );
+    }
+
+    #[test]
+    fn splits_long_messages_at_whitespace() {
+        assert_eq!(
+            split_long_message("the quick brown fox", 10),
+            ["the quick", "brown fox"]
+        );
+        assert_eq!(split_long_message("short", 10), ["short"]);
+        // no whitespace to split at
+        assert_eq!(split_long_message("ąąąąąą b", 4), ["ąąąą", "ąą b"]);
+    }
+
+    #[test]
+    fn removes_think_blocks() {
+        assert_eq!(
+            remove_between_tags(
+                "<think>hmm</think>Hi!<think>again\n</think> Bye",
+                "<think>",
+                "</think>"
+            ),
+            "Hi! Bye"
+        );
+        assert_eq!(
+            remove_between_tags("Hi <think>never ends", "<think>", "</think>"),
+            "Hi "
+        );
+    }
+
+    #[test]
+    fn replaces_self_mentions() {
+        assert_eq!(
+            replace_self_mentions("Ask Bot anything, botanist.", "bot", "me"),
+            "Ask me anything, botanist."
+        );
+        assert_eq!(replace_self_mentions("BOT here, hi", "bot", ""), "here, hi");
+        assert_eq!(
+            replace_self_mentions("bot bot, robot", "bot", ""),
+            ", robot"
+        );
+        assert_eq!(replace_self_mentions("thanks bot!", "bot", ""), "thanks!");
+        // the rest of the whitespace is left alone
+        assert_eq!(
+            replace_self_mentions("a  list:\n  - ask bot here\n  - done", "bot", ""),
+            "a  list:\n  - ask here\n  - done"
+        );
+    }
+
+    #[test]
+    fn extracts_every_memory() {
+        assert_eq!(
+            extract_between_tags(
+                "[mem]a[/mem] hi [mem]b[/mem] [mem]unclosed",
+                "[mem]",
+                "[/mem]"
+            ),
+            ["a", "b"]
+        );
+    }
+
+    #[test]
+    fn strips_memory_tags_and_lead_ins() {
+        assert_eq!(
+            strip_memory_tags(
+                "Got it! I'll remember that: <MEMORY>Tom likes tea</MEMORY>",
+                "<MEMORY>",
+                "</MEMORY>",
+                40
+            ),
+            ""
+        );
+        assert_eq!(
+            strip_memory_tags(
+                "Nice, Tom! <MEMORY>Tom likes tea</MEMORY>\nWhat kind do you drink?",
+                "<MEMORY>",
+                "</MEMORY>",
+                40
+            ),
+            "What kind do you drink?"
+        );
+        assert_eq!(
+            strip_memory_tags(
+                "<MEMORY>Tom likes tea</MEMORY> Tea is great, the green kind especially in summer.",
+                "<MEMORY>",
+                "</MEMORY>",
+                40
+            ),
+            "Tea is great, the green kind especially in summer."
+        );
+        // only the tags go when the cleanup is disabled
+        assert_eq!(
+            strip_memory_tags(
+                "Noted: <MEMORY>Tom likes tea</MEMORY>",
+                "<MEMORY>",
+                "</MEMORY>",
+                0
+            ),
+            "Noted:"
+        );
+        // short replies aren't lead-ins unless asked for
+        assert_eq!(
+            strip_memory_tags(
+                "<MEMORY>Tom likes tea</MEMORY> Noted.",
+                "<MEMORY>",
+                "</MEMORY>",
+                0
+            ),
+            "Noted."
+        );
+        // a memory spanning lines is removed whole
+        assert_eq!(
+            strip_memory_tags(
+                "Sure!\n<MEMORY>\nTom likes tea\n</MEMORY>\nAnything else?",
+                "<MEMORY>",
+                "</MEMORY>",
+                0
+            ),
+            "Sure!\nAnything else?"
+        );
+    }
+}
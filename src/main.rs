@@ -1,39 +1,82 @@
+mod audit;
+mod chat_template;
+mod command;
 mod config;
+mod downscale;
+mod error;
 mod handle_mc_update;
 mod handle_ollama_gen;
+mod memory_file;
+mod memory_retrieval;
+mod metrics;
 mod ollama_api;
+mod ollama_client;
+mod rate_limit;
 mod room_state;
+mod sink;
 mod tls;
 
 use anyhow::Context;
+use audit::AuditLog;
+use chrono::Utc;
 use clap::Parser;
-use config::Config;
-use futures::future::FutureExt;
-use futures::stream::FuturesUnordered;
-use futures::StreamExt;
-use handle_mc_update::handle_mc_update;
-use handle_ollama_gen::handle_ollama_gen;
+use config::{Config, HistoryStrategy};
+use error::Error;
+use handle_mc_update::{handle_mc_update, handle_message, start_generation};
+use handle_ollama_gen::{
+    handle_consolidation, handle_model_switch, handle_ollama_gen, handle_relearn,
+};
+use memory_file::Memories;
+use memory_retrieval::EmbeddingCache;
+use metrics::{Metrics, RoomStatus, Status};
 use multichat_client::proto::Config as ProtoConfig;
 use multichat_client::{ClientBuilder, EitherStream, Update};
-use room_state::{Message, RoomState};
-use std::collections::HashMap;
+use ollama_client::{HttpClient, OllamaClient};
+use room_state::{Message, ModelSettings, OllamaTask, RoomState, SavedMessage};
+use sink::{Multichat, Sink, Stdout};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::{pending, Future};
 use std::path::PathBuf;
 use std::process::ExitCode;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{io, mem, panic};
+use tokio::io::{stdin, AsyncBufReadExt, BufReader, Lines, Stdin};
 use tokio::net::TcpStream;
-use tokio::time::timeout;
-use tokio::{fs, select};
+use tokio::task::{Id, JoinSet};
+use tokio::time::{sleep, sleep_until, timeout};
+use tokio::{fs, select, signal};
 use tokio_rustls::client::TlsStream;
-use tracing::{error, info, subscriber};
+use tracing::{debug, error, info, subscriber, warn};
 use tracing_subscriber::filter::{EnvFilter, LevelFilter};
 use tracing_subscriber::{fmt, prelude::*};
 
 #[derive(Parser)]
 struct Args {
-    #[clap(help = "Path to config file")]
-    config: PathBuf,
+    #[clap(
+        help = "Paths to config files or directories of them, later ones override earlier ones",
+        required = true
+    )]
+    config: Vec<PathBuf>,
+    #[clap(
+        long,
+        help = "Don't connect to Multichat, chat in a single room through stdin/stdout instead"
+    )]
+    repl: bool,
 }
 
+// the room and the users of --repl mode
+const REPL_GROUP: &str = "repl";
+const REPL_GID: u32 = 0;
+const REPL_MY_UID: u32 = 0;
+const REPL_USER_UID: u32 = 1;
+
+// how long to wait between attempts to reconnect to multichat
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+type McClient = multichat_client::Client<EitherStream<TlsStream<TcpStream>>>;
+
 #[tokio::main]
 async fn main() -> ExitCode {
     match run().await {
@@ -51,164 +94,1144 @@ async fn run() -> anyhow::Result<()> {
         .from_env_lossy();
     let registry = tracing_subscriber::registry()
         .with(filter)
-        .with(fmt::layer().without_time().with_target(false));
+        // stdout is where the repl replies go
+        .with(
+            fmt::layer()
+                .without_time()
+                .with_target(false)
+                .with_writer(io::stderr),
+        );
     subscriber::set_global_default(registry).unwrap();
 
     let args = Args::parse();
 
-    info!("Reading config from {}", args.config.display());
+    let mut merged_config = toml::Table::new();
+    for path in config_files(&args.config).await? {
+        info!("Reading config from {}", path.display());
 
-    let config = fs::read_to_string(&args.config)
-        .await
-        .context("reading config")?;
-    let config = toml::from_str::<Config>(&config).context("parsing config")?;
+        let config = fs::read_to_string(&path)
+            .await
+            .with_context(|| format!("reading config {}", path.display()))?;
+        let config = toml::from_str::<toml::Table>(&config)
+            .with_context(|| format!("parsing config {}", path.display()))?;
 
-    let mut state = State::create(config).await.context("initialization")?;
+        config::merge(&mut merged_config, config);
+    }
+    let config = toml::Value::Table(merged_config)
+        .try_into::<Config>()
+        .context("parsing config")?;
+    config.validate(args.repl).context("invalid config")?;
+
+    let mut state = State::create(config, args.repl)
+        .await
+        .context("initialization")?;
 
-    info!("Connected to Multichat");
+    let mut repl_input = if args.repl {
+        info!("Chatting in the repl, messages are read from stdin");
+        Some(BufReader::new(stdin()).lines())
+    } else {
+        info!("Connected to Multichat");
+        None
+    };
 
+    // Fairness policy: when both a Multichat update and a finished generation (or a due debounce
+    // timer) are ready at the same time, the kind of event that was *not* handled last goes first. Under sustained load
+    // this interleaves the two sources instead of letting one of them monopolize the loop.
+    // Rooms need no extra care: finished tasks of all rooms come out of a single JoinSet in the
+    // order they completed, so no room can starve the others.
+    let mut prefer_multichat = false;
+    // after a signal, only the replies being generated are waited for, so that they aren't lost
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    let mut shutting_down = false;
     loop {
-        // we either wait for an update from multichat or
-        // the Ollama endpoint to finish generating a response in any of the groups
+        if shutting_down && !state.is_replying() {
+            break;
+        }
+
+        // we either wait for an update from multichat,
+        // the Ollama endpoint to finish generating a response in any of the groups,
+        // or a debounced generation to become due
         enum EventType {
             FinishGenerate {
                 gid: u32,
+                kind: TaskKind,
+                id: Id,
                 res: anyhow::Result<String>,
             },
             Multichat {
                 update: Update,
             },
+            Disconnected {
+                error: anyhow::Error,
+            },
+            Repl {
+                line: Option<String>,
+            },
+            Debounced {
+                gid: u32,
+            },
+            Shutdown,
         }
 
         let event = {
-            let mut ollama_api_tasks: FuturesUnordered<_> = state
+            // the room whose debounced generation is due first, if any
+            let next_debounced = state
                 .rooms
-                .iter_mut()
-                .filter_map(|(gid, room)| {
-                    room.ollama_api_task
-                        .as_mut()
-                        .map(|join| join.map(|r| (*gid, r)))
-                })
-                .collect();
+                .iter()
+                .filter_map(|(gid, room)| room.pending_generation.map(|at| (*gid, at)))
+                .min_by_key(|(_gid, at)| *at);
+            let debounce_timer = async move {
+                match next_debounced {
+                    Some((gid, at)) => {
+                        sleep_until(at.into()).await;
+                        gid
+                    }
+                    None => pending().await,
+                }
+            };
 
-            select! {
-                Some((gid, res)) = ollama_api_tasks.next(), if !ollama_api_tasks.is_empty() => {
-                    let res = res.unwrap(); // we unwrap the JoinError, since it would only be err if it panicked
-                    EventType::FinishGenerate { gid, res }
+            if prefer_multichat {
+                select! {
+                    biased;
+                    signal = &mut shutdown, if !shutting_down => {
+                        signal.context("listening for signals")?;
+                        EventType::Shutdown
+                    }
+                    update = next_update(state.sink.as_mut()), if !shutting_down => match update {
+                        Ok(update) => EventType::Multichat { update },
+                        Err(error) => EventType::Disconnected { error },
+                    },
+                    line = next_line(&mut repl_input), if !shutting_down => {
+                        EventType::Repl { line: line.context("reading stdin")? }
+                    }
+                    (id, (gid, kind, res)) = next_task(&mut state.tasks) => {
+                        EventType::FinishGenerate { gid, kind, id, res }
+                    }
+                    gid = debounce_timer, if !shutting_down => {
+                        EventType::Debounced { gid }
+                    }
                 }
-                update = state.mc_client.read_update() => {
-                    EventType::Multichat { update: update.context("multichat update")? }
+            } else {
+                select! {
+                    biased;
+                    signal = &mut shutdown, if !shutting_down => {
+                        signal.context("listening for signals")?;
+                        EventType::Shutdown
+                    }
+                    (id, (gid, kind, res)) = next_task(&mut state.tasks) => {
+                        EventType::FinishGenerate { gid, kind, id, res }
+                    }
+                    gid = debounce_timer, if !shutting_down => {
+                        EventType::Debounced { gid }
+                    }
+                    update = next_update(state.sink.as_mut()), if !shutting_down => match update {
+                        Ok(update) => EventType::Multichat { update },
+                        Err(error) => EventType::Disconnected { error },
+                    },
+                    line = next_line(&mut repl_input), if !shutting_down => {
+                        EventType::Repl { line: line.context("reading stdin")? }
+                    }
                 }
             }
         };
+        prefer_multichat = !matches!(
+            event,
+            EventType::Multichat { .. } | EventType::Disconnected { .. } | EventType::Repl { .. }
+        );
 
-        match event {
-            EventType::Multichat { update } => {
-                handle_mc_update(&mut state, update).await?;
+        let result = match event {
+            EventType::Multichat { update } => handle_mc_update(&mut state, update).await,
+            EventType::Disconnected { error } => Err(Error::multichat(error)),
+            EventType::Shutdown => {
+                info!("Shutting down gracefully");
+                shutting_down = true;
+                state.stop_background_tasks();
+                Ok(())
             }
-            EventType::FinishGenerate { gid, res } => {
-                handle_ollama_gen(&mut state, gid, res).await?;
+            EventType::Repl { line: Some(line) } => {
+                handle_message(&mut state, REPL_GID, REPL_USER_UID, &line, Vec::new()).await
+            }
+            EventType::Repl { line: None } => {
+                info!("End of input, exiting once the replies are done");
+                shutting_down = true;
+                // what was asked for before the end is still answered
+                let due: Vec<u32> = state
+                    .rooms
+                    .iter()
+                    .filter(|(_gid, room)| room.pending_generation.is_some())
+                    .map(|(gid, _room)| *gid)
+                    .collect();
+                let result = due
+                    .into_iter()
+                    .try_for_each(|gid| start_generation(&mut state, gid, false));
+                state.stop_background_tasks();
+                result.map_err(Error::from)
+            }
+            EventType::FinishGenerate { gid, kind, id, .. }
+                if !state.is_current_task(gid, kind, id) =>
+            {
+                // replaced by a newer request in the meantime
+                debug!("Ignoring the result of an outdated task in group {gid}");
+                Ok(())
+            }
+            EventType::FinishGenerate {
+                gid,
+                kind: TaskKind::Reply,
+                res,
+                ..
+            } => handle_ollama_gen(&mut state, gid, res).await,
+            EventType::FinishGenerate {
+                gid,
+                kind: TaskKind::Consolidation,
+                res,
+                ..
+            } => handle_consolidation(&mut state, gid, res).await,
+            EventType::FinishGenerate {
+                gid,
+                kind: TaskKind::Relearn,
+                res,
+                ..
+            } => handle_relearn(&mut state, gid, res).await,
+            EventType::FinishGenerate {
+                gid,
+                kind: TaskKind::ModelSwitch,
+                res,
+                ..
+            } => handle_model_switch(&mut state, gid, res).await,
+            EventType::Debounced { gid } => {
+                start_generation(&mut state, gid, false).map_err(Error::from)
+            }
+        };
+
+        match result {
+            Ok(()) => {}
+            Err(e) if e.is_connection_lost() && shutting_down => {
+                // the replies couldn't be sent anyway
+                warn!("{e}. Not reconnecting while shutting down");
+                state.cancel_replies();
+            }
+            Err(e) if e.is_connection_lost() => {
+                warn!("{e}. Reconnecting");
+                let interrupted = select! {
+                    result = state.reconnect() => {
+                        result.context("reconnecting to multichat")?;
+                        false
+                    }
+                    signal = &mut shutdown => {
+                        signal.context("listening for signals")?;
+                        true
+                    }
+                };
+                if interrupted {
+                    info!("Shutting down without reconnecting");
+                    shutting_down = true;
+                    state.stop_background_tasks();
+                    state.cancel_replies();
+                }
+            }
+            Err(e) => error!("{e}"),
+        }
+
+        state.unload_idle_rooms().await;
+        state.update_status();
+    }
+
+    // what's left are aborted tasks, which may still hold on to an audit sender
+    state.tasks.shutdown().await;
+    if let Some(audit) = state.audit.take() {
+        audit.close().await;
+    }
+
+    // a read-only memory file is no reason to exit with an error
+    if state.memories_unsaved {
+        if let Err(e) = state.flush_memories().await {
+            error!("Failed to save memories before exiting. {e}");
+        }
+    }
+
+    Ok(())
+}
+
+/// What an ollama api task was spawned for
+#[derive(Clone, Copy, PartialEq)]
+enum TaskKind {
+    Reply,
+    Consolidation,
+    Relearn,
+    ModelSwitch,
+}
+
+type TaskOutput = (u32, TaskKind, anyhow::Result<String>);
+
+/// The next finished ollama api task, skipping aborted ones
+async fn next_task(tasks: &mut JoinSet<TaskOutput>) -> (Id, TaskOutput) {
+    loop {
+        match tasks.join_next_with_id().await {
+            Some(Ok(finished)) => return finished,
+            Some(Err(e)) if e.is_cancelled() => {}
+            // otherwise it would only be err if it panicked
+            Some(Err(e)) => panic::resume_unwind(e.into_panic()),
+            None => pending().await,
+        }
+    }
+}
+
+/// The history file of a room, with anything that's not safe in a file name escaped
+fn history_file_name(room_name: &str) -> String {
+    let mut name: String = room_name
+        .bytes()
+        .map(|b| match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => (b as char).to_string(),
+            b => format!("%{b:02x}"),
+        })
+        .collect();
+    name.push_str(".json");
+
+    name
+}
+
+/// Builds the state of a room, with the group's overrides applied
+fn new_room(
+    config: &Config,
+    group_name: &str,
+    my_uid: u32,
+    memories: Vec<String>,
+    warmups: &mut HashSet<(String, String)>,
+) -> RoomState {
+    let overrides = config.ollama.groups.get(group_name);
+    let mention_name = overrides
+        .and_then(|overrides| overrides.mention_name.clone())
+        .unwrap_or_else(|| config.ollama.mention_name.clone());
+    let observe_only = overrides
+        .map(|overrides| overrides.observe_only)
+        .unwrap_or(false);
+    let allow_memory_creation = overrides
+        .map(|overrides| overrides.allow_memory_creation)
+        .unwrap_or(true);
+    let keep_alive = overrides
+        .and_then(|overrides| overrides.keep_alive.clone())
+        .unwrap_or_else(|| config.ollama.keep_alive.clone());
+    let warmup = overrides
+        .and_then(|overrides| overrides.warmup)
+        .unwrap_or(config.ollama.warmup);
+    let settings = ModelSettings {
+        model: overrides
+            .and_then(|overrides| overrides.model.clone())
+            .unwrap_or_else(|| config.ollama.model.clone()),
+        keep_alive,
+        system_prompt: overrides
+            .and_then(|overrides| overrides.system_prompt.as_ref())
+            .unwrap_or(&config.ollama.system_prompt)
+            .segments()
+            .to_vec(),
+        temperature: overrides
+            .and_then(|overrides| overrides.temperature)
+            .unwrap_or(config.ollama.temperature),
+        top_k: overrides
+            .and_then(|overrides| overrides.top_k)
+            .unwrap_or(config.ollama.top_k),
+    };
+
+    if warmup {
+        // each model only needs to be loaded once per keep-alive, rooms can differ in it
+        warmups.insert((settings.model.clone(), settings.keep_alive.clone()));
+    }
+
+    RoomState::new(
+        my_uid,
+        group_name.to_owned(),
+        mention_name,
+        observe_only,
+        allow_memory_creation,
+        settings,
+        memories,
+    )
+}
+
+/// A fresh connection to multichat, with the configured groups joined
+struct Connection {
+    client: McClient,
+    // gid -> name of every group on the server
+    group_names: HashMap<u32, String>,
+    // (name, gid, my uid) of each configured group
+    joined: Vec<(String, u32, u32)>,
+}
+
+/// Errors that retrying won't fix are [`Error::Fatal`]
+async fn connect(config: &Config) -> Result<Connection, Error> {
+    let mc_connector = match &config.multichat.certificate {
+        Some(certificate) => Some(
+            tls::configure(certificate)
+                .await
+                .context("TLS init")
+                .map_err(Error::Fatal)?,
+        ),
+        None => None,
+    };
+
+    let mut proto_config = ProtoConfig::default();
+    proto_config.max_size(512 * 1024 * 1024); // 512 MiB
+
+    // the multichat protocol has no session resumption - connecting only gives us the
+    // group list, so after losing the connection all groups and users have to be joined again
+    let connected = timeout(
+        Duration::from_secs(5),
+        ClientBuilder::maybe_tls(mc_connector)
+            .config(proto_config)
+            .connect(&config.multichat.server, config.multichat.access_token),
+    )
+    .await
+    .context("connection timed out")
+    .map_err(Error::multichat)?;
+    let (groups, mut client) = match connected {
+        Ok(connected) => connected,
+        Err(e) => {
+            // the client doesn't tell a rejected access token apart from other failures,
+            // but a server that can be reached and still refuses us won't change its mind
+            let e = anyhow::Error::from(e).context("connection to multichat failed");
+            return Err(match TcpStream::connect(&config.multichat.server).await {
+                Ok(_) => Error::Fatal(e.context("server is up, check access-token")),
+                Err(_) => Error::multichat(e),
+            });
+        }
+    };
+
+    let mut joined = Vec::new();
+    for group_name in &config.multichat.groups {
+        let gid = *groups
+            .get(group_name.as_str())
+            .with_context(|| format!("Group not found: {group_name}"))
+            .map_err(Error::Fatal)?;
+        client.join_group(gid).await.map_err(Error::multichat)?;
+
+        let my_uid = client
+            .join_user(gid, &config.multichat.user_name)
+            .await
+            .map_err(Error::multichat)?;
+
+        joined.push((group_name.clone(), gid, my_uid));
+    }
+
+    let group_names = groups
+        .iter()
+        .map(|(name, gid)| (*gid, name.to_string()))
+        .collect();
+
+    Ok(Connection {
+        client,
+        group_names,
+        joined,
+    })
+}
+
+/// Resolves on ctrl-c, or SIGTERM on unix
+async fn shutdown_signal() -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut sigterm = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+        select! {
+            result = signal::ctrl_c() => result,
+            _ = sigterm.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    signal::ctrl_c().await
+}
+
+async fn next_update(sink: &mut dyn Sink) -> anyhow::Result<Update> {
+    match sink.client() {
+        Some(mc_client) => mc_client.read_update().await.context("multichat update"),
+        None => pending().await,
+    }
+}
+
+async fn next_line(input: &mut Option<Lines<BufReader<Stdin>>>) -> io::Result<Option<String>> {
+    match input {
+        Some(input) => input.next_line().await,
+        None => pending().await,
+    }
+}
+
+/// Expands directories into the `.toml` files they contain, in alphabetical order
+async fn config_files(paths: &[PathBuf]) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for path in paths {
+        let metadata = fs::metadata(path)
+            .await
+            .with_context(|| format!("reading config {}", path.display()))?;
+        if !metadata.is_dir() {
+            files.push(path.clone());
+            continue;
+        }
+
+        let mut dir_files = Vec::new();
+        let mut entries = fs::read_dir(path).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let entry_path = entry.path();
+            if entry_path.extension().is_some_and(|ext| ext == "toml") {
+                dir_files.push(entry_path);
             }
         }
+        dir_files.sort();
+
+        files.extend(dir_files);
     }
+
+    Ok(files)
 }
 
 struct State {
-    mc_client: multichat_client::Client<EitherStream<TlsStream<TcpStream>>>,
-    reqw: reqwest::Client,
+    // multichat, or stdout in repl mode
+    sink: Box<dyn Sink>,
+    // shared with the generation tasks, which do the memory retrieval
+    ollama: Arc<dyn OllamaClient>,
     config: Config,
+    metrics: Option<Arc<Metrics>>,
+    audit: Option<AuditLog>,
+    // all running ollama api tasks, their handles are kept by the rooms
+    tasks: JoinSet<TaskOutput>,
+    // writing the memory file failed, so memories are only kept for this session
+    memories_read_only: bool,
+    // memories changed since they were last written
+    memories_unsaved: bool,
+    // memories of groups that don't have a room, kept so that saving doesn't lose them
+    other_memories: Memories,
+    // gid -> name of every group on the server
+    group_names: HashMap<u32, String>,
+    // for memory retrieval
+    memory_embeddings: EmbeddingCache,
 
     // group id -> room data
+    //
+    // there is a single multichat connection, so a gid identifies a room. names are only
+    // used where things have to survive a restart (config, memory file), and are unique
+    // per server. supporting more servers would mean keying these by (server, gid) and
+    // the memory file by (server, name)
     rooms: HashMap<u32, RoomState>,
 }
 
 impl State {
-    pub async fn create(config: Config) -> anyhow::Result<Self> {
-        let mut memories: HashMap<String, Vec<String>> = serde_json::from_str(
+    pub async fn create(config: Config, repl: bool) -> anyhow::Result<Self> {
+        let mut memories = memory_file::parse(
+            config.ollama.memory_format,
             &fs::read_to_string(&config.ollama.memory_file)
                 .await
                 .context("reading memory file")?,
         )
         .context("parsing memory file")?;
 
-        let mc_connector = match &config.multichat.certificate {
-            Some(certificate) => Some(tls::configure(certificate).await.context("TLS init")?),
+        let mut rooms = HashMap::new();
+        let mut warmups = HashSet::new();
+        let (sink, group_names): (Box<dyn Sink>, _) = if repl {
+            let mut room = new_room(
+                &config,
+                REPL_GROUP,
+                REPL_MY_UID,
+                memories.remove(REPL_GROUP).unwrap_or_default(),
+                &mut warmups,
+            );
+            room.usernames.insert(REPL_USER_UID, "user".to_owned());
+            rooms.insert(REPL_GID, room);
+
+            (Box::new(Stdout), HashMap::new())
+        } else {
+            let connection = connect(&config).await?;
+            for (group_name, gid, my_uid) in connection.joined {
+                let room = new_room(
+                    &config,
+                    &group_name,
+                    my_uid,
+                    memories.remove(&group_name).unwrap_or_default(),
+                    &mut warmups,
+                );
+                rooms.insert(gid, room);
+            }
+
+            (
+                Box::new(Multichat {
+                    client: Some(connection.client),
+                }),
+                connection.group_names,
+            )
+        };
+
+        let metrics = match &config.metrics {
+            Some(metrics_config) => {
+                let metrics = Arc::new(Metrics::default());
+                for room in rooms.values() {
+                    metrics.set_room_memories(&room.room_name, room.memories.len());
+                }
+                metrics::serve(metrics_config.listen, metrics.clone())
+                    .await
+                    .context("metrics endpoint")?;
+
+                Some(metrics)
+            }
             None => None,
         };
 
-        let mut proto_config = ProtoConfig::default();
-        proto_config.max_size(512 * 1024 * 1024); // 512 MiB
+        let audit = match &config.ollama.audit_file {
+            Some(audit_file) => Some(
+                AuditLog::open(audit_file, config.ollama.audit_redact_images)
+                    .await
+                    .context("opening audit file")?,
+            ),
+            None => None,
+        };
 
-        let (groups, mut mc_client) = timeout(
-            Duration::from_secs(5),
-            ClientBuilder::maybe_tls(mc_connector)
-                .config(proto_config)
-                .connect(&config.multichat.server, config.multichat.access_token),
-        )
-        .await
-        .context("connection timed out")?
-        .context("connection to multichat")?;
+        let state = Self {
+            sink,
+            ollama: Arc::new(HttpClient::new(&config.ollama)),
+            config,
+            metrics,
+            audit,
+            tasks: JoinSet::new(),
+            memories_read_only: false,
+            memories_unsaved: false,
+            other_memories: memories,
+            group_names,
+            memory_embeddings: EmbeddingCache::default(),
+            rooms,
+        };
 
-        let mut rooms = HashMap::new();
-        for group_name in &config.multichat.groups {
-            let gid = *groups.get(group_name.as_str()).context("Group not found")?;
-            mc_client.join_group(gid).await?;
+        for (model, keep_alive) in warmups {
+            state.ollama.warmup(model, keep_alive);
+        }
+        state.update_status();
 
-            let my_uid = mc_client
-                .join_user(gid, &config.multichat.user_name)
-                .await?;
+        Ok(state)
+    }
+    pub async fn send_message(&mut self, gid: u32, uid: u32, message: &str) -> Result<(), Error> {
+        self.sink.send_message(gid, uid, message).await
+    }
+    /// Connects to multichat again, retrying until it works or fails for good. The rooms are
+    /// kept, only what's specific to the connection is reset
+    async fn reconnect(&mut self) -> Result<(), Error> {
+        self.sink = Box::new(Multichat { client: None });
 
-            rooms.insert(
-                gid,
-                RoomState::new(
+        let mut backoff = RECONNECT_BACKOFF_MIN;
+        let connection = loop {
+            match connect(&self.config).await {
+                Ok(connection) => break connection,
+                Err(e) if e.is_fatal() => return Err(e),
+                Err(e) => {
+                    warn!("Failed to reconnect to multichat, retrying in {backoff:?}. {e:#}");
+                    sleep(backoff).await;
+                    backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+                }
+            }
+        };
+
+        // gids could have changed if the server restarted, so rooms are matched by name
+        let mut old_rooms: HashMap<String, (u32, RoomState)> = self
+            .rooms
+            .drain()
+            .map(|(gid, room)| (room.room_name.clone(), (gid, room)))
+            .collect();
+        let mut warmups = HashSet::new();
+        for (group_name, gid, my_uid) in connection.joined {
+            let room = match old_rooms.remove(&group_name) {
+                Some((old_gid, mut room)) => {
+                    if old_gid != gid {
+                        // results of these would come for the old gid
+                        for task in [
+                            room.ollama_api_task.take(),
+                            room.consolidation_task.take(),
+                            room.relearn_task.take(),
+                            room.model_switch_task.take(),
+                        ]
+                        .into_iter()
+                        .flatten()
+                        {
+                            task.abort();
+                        }
+                    }
+                    room.my_uid = my_uid;
+                    room.usernames = HashMap::new();
+
+                    room
+                }
+                None => new_room(
+                    &self.config,
+                    &group_name,
                     my_uid,
-                    group_name.clone(),
-                    memories.remove(group_name).unwrap_or(Vec::new()),
+                    self.other_memories.remove(&group_name).unwrap_or_default(),
+                    &mut warmups,
                 ),
-            );
+            };
+            self.rooms.insert(gid, room);
+        }
+        // rooms of groups that aren't configured are created again when they're heard from
+        for (group_name, (_gid, room)) in old_rooms {
+            self.other_memories.insert(group_name, room.memories);
+        }
+        for (model, keep_alive) in warmups {
+            self.ollama.warmup(model, keep_alive);
         }
 
-        Ok(Self {
-            mc_client,
-            reqw: reqwest::Client::new(),
-            config,
-            rooms,
-        })
+        self.sink = Box::new(Multichat {
+            client: Some(connection.client),
+        });
+        self.group_names = connection.group_names;
+        info!("Reconnected to Multichat");
+
+        Ok(())
+    }
+    /// Creates a room for a group that isn't configured, when it turns out the bot is in it
+    pub async fn add_room(&mut self, gid: u32) -> Result<(), Error> {
+        let group_name = self
+            .group_names
+            .get(&gid)
+            .cloned()
+            .context("unknown group")?;
+        let my_uid = self
+            .sink
+            .client()
+            .context("no multichat connection")?
+            .join_user(gid, &self.config.multichat.user_name)
+            .await
+            .map_err(Error::multichat)?;
+
+        let mut warmups = HashSet::new();
+        let room = new_room(
+            &self.config,
+            &group_name,
+            my_uid,
+            self.other_memories.remove(&group_name).unwrap_or_default(),
+            &mut warmups,
+        );
+        for (model, keep_alive) in warmups {
+            self.ollama.warmup(model, keep_alive);
+        }
+
+        info!("Joined group {group_name}, which isn't configured");
+        self.rooms.insert(gid, room);
+
+        Ok(())
+    }
+    /// Runs an ollama api request in the background, the result is handled in the main loop
+    pub fn spawn_task(
+        &mut self,
+        gid: u32,
+        kind: TaskKind,
+        request: impl Future<Output = anyhow::Result<String>> + Send + 'static,
+    ) -> OllamaTask {
+        self.tasks.spawn(async move { (gid, kind, request.await) })
+    }
+    /// Whether a reply is being generated in any room
+    fn is_replying(&self) -> bool {
+        self.rooms
+            .values()
+            .any(|room| room.ollama_api_task.is_some())
+    }
+    /// Stops everything but the replies being generated, which are still worth waiting for
+    fn stop_background_tasks(&mut self) {
+        for room in self.rooms.values_mut() {
+            room.pending_generation = None;
+            for task in [
+                room.consolidation_task.take(),
+                room.relearn_task.take(),
+                room.model_switch_task.take(),
+            ]
+            .into_iter()
+            .flatten()
+            {
+                task.abort();
+            }
+        }
+    }
+    fn cancel_replies(&mut self) {
+        for room in self.rooms.values_mut() {
+            room.generation_started = None;
+            if let Some(task) = room.ollama_api_task.take() {
+                task.abort();
+            }
+        }
+    }
+    /// Whether the task is the one the room is waiting for
+    fn is_current_task(&self, gid: u32, kind: TaskKind, id: Id) -> bool {
+        let Some(room) = self.rooms.get(&gid) else {
+            return false;
+        };
+        let task = match kind {
+            TaskKind::Reply => &room.ollama_api_task,
+            TaskKind::Consolidation => &room.consolidation_task,
+            TaskKind::Relearn => &room.relearn_task,
+            TaskKind::ModelSwitch => &room.model_switch_task,
+        };
+
+        task.as_ref().map(|task| task.id()) == Some(id)
+    }
+    /// Refreshes the snapshot served on /status
+    fn update_status(&self) {
+        let Some(metrics) = &self.metrics else {
+            return;
+        };
+
+        metrics.set_status(Status {
+            model: self.config.ollama.model.clone(),
+            rooms: self
+                .rooms
+                .values()
+                .map(|room| RoomStatus {
+                    name: room.room_name.clone(),
+                    model: room.settings.model.clone(),
+                    generating: room.ollama_api_task.is_some(),
+                    last_response: room.last_response.map(|time| time.to_rfc3339()),
+                    memories: room.memories.len(),
+                })
+                .collect(),
+        });
     }
-    pub async fn add_memory(&mut self, gid: u32, memory: String) -> anyhow::Result<()> {
-        self.rooms.get_mut(&gid).unwrap().memories.push(memory);
+    pub async fn add_memory(&mut self, gid: u32, memory: String) -> Result<(), Error> {
+        self.add_memories(gid, vec![memory]).await
+    }
+    pub async fn add_memories(&mut self, gid: u32, memories: Vec<String>) -> Result<(), Error> {
+        let room = self.rooms.get_mut(&gid).unwrap();
+
+        if let Some(metrics) = &self.metrics {
+            for _ in &memories {
+                metrics.memory_added();
+            }
+        }
+        let added = memories.len();
+        room.memories.extend(memories);
+        if let Some(metrics) = &self.metrics {
+            metrics.set_room_memories(&room.room_name, room.memories.len());
+        }
+        self.log_activity(gid, format!("{added} memories created"));
 
         // save
         self.save_memories().await
     }
-    pub async fn remove_memory(&mut self, gid: u32, idx: usize) -> anyhow::Result<String> {
-        let mem = self.rooms.get_mut(&gid).unwrap().memories.remove(idx);
+    pub async fn remove_memory(&mut self, gid: u32, idx: usize) -> Result<String, Error> {
+        let room = self.rooms.get_mut(&gid).unwrap();
+        let mem = room.memories.remove(idx);
+
+        if let Some(metrics) = &self.metrics {
+            metrics.memory_removed();
+            metrics.set_room_memories(&room.room_name, room.memories.len());
+        }
+        self.evict_embeddings();
 
         // save
         self.save_memories().await?;
 
         Ok(mem)
     }
-    async fn save_memories(&self) -> anyhow::Result<()> {
-        let all_memories: HashMap<String, Vec<String>> = self
-            .rooms
-            .iter()
-            .map(|(_gid, room)| (room.room_name.clone(), room.memories.clone()))
+    pub async fn replace_memories(&mut self, gid: u32, memories: Vec<String>) -> Result<(), Error> {
+        let room = self.rooms.get_mut(&gid).unwrap();
+        room.memories = memories;
+
+        if let Some(metrics) = &self.metrics {
+            metrics.set_room_memories(&room.room_name, room.memories.len());
+        }
+        self.evict_embeddings();
+
+        // save
+        self.save_memories().await
+    }
+    /// Forgets the embeddings of memories that are gone
+    fn evict_embeddings(&self) {
+        let rooms = self.rooms.values().map(|room| &room.memories);
+        let kept: HashSet<&String> = rooms
+            .chain(self.other_memories.values())
+            .flatten()
             .collect();
-        fs::write(
-            &self.config.ollama.memory_file,
-            &serde_json::to_string_pretty(&all_memories)?,
-        )
-        .await?;
+        self.memory_embeddings
+            .lock()
+            .unwrap()
+            .retain(|memory, _| kept.contains(memory));
+    }
+    async fn save_memories(&mut self) -> Result<(), Error> {
+        self.memories_unsaved = true;
+        if self.memories_read_only {
+            return Ok(());
+        }
+
+        // e.g. mounted read-only, keep going without persisting rather than failing every time
+        match self.write_memories().await {
+            Ok(()) => self.memories_unsaved = false,
+            Err(e) => {
+                warn!("Failed to write memory file, memories won't be saved from now on. {e}");
+                self.memories_read_only = true;
+            }
+        }
+
+        Ok(())
+    }
+    /// Saves the memories right away, even if saving has failed before
+    pub async fn flush_memories(&mut self) -> Result<(), Error> {
+        self.write_memories().await?;
+        self.memories_read_only = false;
+        self.memories_unsaved = false;
 
         Ok(())
     }
+    async fn write_memories(&self) -> Result<(), Error> {
+        let mut all_memories = self.other_memories.clone();
+        all_memories.extend(
+            self.rooms
+                .iter()
+                .map(|(_gid, room)| (room.room_name.clone(), room.memories.clone())),
+        );
+        let serialized = memory_file::serialize(self.config.ollama.memory_format, &all_memories)
+            .map_err(Error::Memory)?;
+        memory_file::write_atomically(&self.config.ollama.memory_file, &serialized)
+            .await
+            .map_err(|e| Error::Memory(e.into()))?;
+
+        Ok(())
+    }
+    /// Records an event in the room's activity log, forgetting the oldest ones
+    pub fn log_activity(&mut self, gid: u32, event: String) {
+        let size = self.config.ollama.activity_log_size;
+        let Some(room) = self.rooms.get_mut(&gid) else {
+            return;
+        };
+
+        room.activity.push_back((Utc::now(), event));
+        while room.activity.len() > size {
+            room.activity.pop_front();
+        }
+    }
+    /// Saves the history of rooms that have been idle for too long to disk and drops it, their
+    /// images being the bulk of what a room holds. The memories stay, they're needed for /mems
+    /// and the status anyway
+    async fn unload_idle_rooms(&mut self) {
+        let Some(idle_secs) = self.config.ollama.unload_idle_rooms_after_secs else {
+            return;
+        };
+
+        let history_dir = self.history_dir();
+        for room in self.rooms.values_mut() {
+            let busy = room.ollama_api_task.is_some()
+                || room.pending_generation.is_some()
+                || room.consolidation_task.is_some()
+                || room.pending_consolidation.is_some()
+                || room.relearn_task.is_some()
+                || room.pending_relearn.is_some();
+            let idle = room.last_active.elapsed() >= Duration::from_secs(idle_secs);
+            if room.message_history.is_empty() || busy || !idle {
+                continue;
+            }
+
+            let saved: Vec<SavedMessage> = room.message_history.iter().map(Into::into).collect();
+            let path = history_dir.join(history_file_name(&room.room_name));
+            let written = async {
+                let serialized = serde_json::to_string(&saved)?;
+                fs::create_dir_all(&history_dir).await?;
+                memory_file::write_atomically(&path, &serialized).await?;
+
+                anyhow::Ok(())
+            };
+            if let Err(e) = written.await {
+                // better to keep it in memory than to lose it
+                warn!(
+                    "Failed to save the history of idle room {}. {e:#}",
+                    room.room_name
+                );
+                continue;
+            }
+
+            debug!("Unloaded the history of idle room {}", room.room_name);
+            room.message_history = VecDeque::new();
+            room.last_message_by_user = HashMap::new();
+            room.history_unloaded = true;
+        }
+    }
+    /// Loads the history of a room back, if it was unloaded for being idle
+    pub async fn load_history(&mut self, gid: u32) {
+        let history_dir = self.history_dir();
+        let Some(room) = self
+            .rooms
+            .get_mut(&gid)
+            .filter(|room| room.history_unloaded)
+        else {
+            return;
+        };
+        room.history_unloaded = false;
+
+        let path = history_dir.join(history_file_name(&room.room_name));
+        let loaded = async {
+            let saved: Vec<SavedMessage> = serde_json::from_str(&fs::read_to_string(&path).await?)?;
+            fs::remove_file(&path).await?;
+
+            anyhow::Ok(saved)
+        };
+        match loaded.await {
+            Ok(saved) => {
+                debug!("Loaded the history of room {}", room.room_name);
+                // anything said since comes after it
+                let newer = mem::take(&mut room.message_history);
+                room.message_history = saved.into_iter().map(Message::from).collect();
+                room.message_history.extend(newer);
+            }
+            Err(e) => warn!(
+                "Failed to load the history of room {}, going on without it. {e:#}",
+                room.room_name
+            ),
+        }
+    }
+    /// Where the histories of idle rooms go, next to the memory file
+    fn history_dir(&self) -> PathBuf {
+        let mut dir = self.config.ollama.memory_file.clone().into_os_string();
+        dir.push(".history");
+
+        dir.into()
+    }
     pub fn push_message(&mut self, gid: u32, msg: Message) {
+        self.rooms.get_mut(&gid).unwrap().last_active = Instant::now();
+        if !msg.was_me {
+            let from = msg.username.as_deref().unwrap_or("unknown user");
+            self.log_activity(gid, format!("message from {from}"));
+        }
+
         let room = self.rooms.get_mut(&gid).unwrap();
 
+        if msg.was_me {
+            room.messages_since_reply = 0;
+        } else {
+            room.received_messages += 1;
+            room.messages_since_reply += 1;
+        }
         if room.message_history.len() == self.config.ollama.prompt_messages_n {
-            room.message_history.pop_front();
+            let oldest = match self.config.ollama.history_strategy {
+                HistoryStrategy::Recent => 0,
+                // keep the opening of the conversation
+                HistoryStrategy::Sample => self
+                    .config
+                    .ollama
+                    .history_head_messages
+                    .min(room.message_history.len().saturating_sub(1)),
+            };
+            room.message_history.remove(oldest);
         }
         room.message_history.push_back(msg);
+
+        // only keep the data of the most recent images, older ones just leave a marker behind
+        for msg in room
+            .message_history
+            .iter_mut()
+            .rev()
+            .filter(|msg| !msg.images.is_empty())
+            .skip(self.config.ollama.max_history_images)
+        {
+            msg.drop_images();
+        }
+
+        // and across all rooms, drop the oldest images until they fit in the budget
+        if let Some(budget) = self.config.ollama.max_image_memory_bytes {
+            while self.image_memory() > budget {
+                let oldest = self
+                    .rooms
+                    .values_mut()
+                    .flat_map(|room| room.message_history.iter_mut())
+                    .filter(|msg| !msg.images.is_empty())
+                    .min_by_key(|msg| msg.time);
+                match oldest {
+                    Some(msg) => msg.drop_images(),
+                    None => break,
+                }
+            }
+        }
+    }
+    /// Bytes of image data held in all rooms' histories
+    pub fn image_memory(&self) -> usize {
+        self.rooms
+            .values()
+            .flat_map(|room| room.message_history.iter())
+            .flat_map(|msg| msg.images.iter())
+            .map(|image| image.len())
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ollama_api::RequestBody;
+    use crate::ollama_client::TestClient;
+
+    /// A repl state with the example config, answered by the client. The memory file is in
+    /// a directory of its own, to be removed at the end
+    pub(crate) async fn test_state(name: &str, client: Arc<TestClient>) -> (State, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut config = toml::from_str::<Config>(include_str!("../example/config.toml")).unwrap();
+        config.ollama.memory_file = dir.join("memories.json");
+        std::fs::write(&config.ollama.memory_file, "{}").unwrap();
+
+        let mut state = State::create(config, true).await.unwrap();
+        state.ollama = client;
+
+        (state, dir)
+    }
+
+    #[tokio::test]
+    async fn mention_gets_a_reply_and_saves_memories() {
+        let client = Arc::new(TestClient::new(
+            "Sure!\n<MEMORY>user likes tea</MEMORY>\n\nGreen or black?",
+        ));
+        let (mut state, dir) = test_state("pipeline-test", client.clone()).await;
+
+        handle_message(
+            &mut state,
+            REPL_GID,
+            REPL_USER_UID,
+            "hey ollama, I like tea",
+            Vec::new(),
+        )
+        .await
+        .unwrap();
+        let (_id, (gid, kind, res)) = next_task(&mut state.tasks).await;
+        assert!(kind == TaskKind::Reply);
+        handle_ollama_gen(&mut state, gid, res).await.unwrap();
+
+        let requests = client.requests.lock().unwrap();
+        let RequestBody::Chat(request) = &requests[0] else {
+            panic!("expected a chat request");
+        };
+        assert_eq!(request.messages[0].role, "system");
+        assert!(request
+            .messages
+            .last()
+            .unwrap()
+            .content
+            .ends_with("user: hey ollama, I like tea"));
+
+        let room = &state.rooms[&REPL_GID];
+        assert_eq!(room.memories, ["user likes tea"]);
+        assert!(room.message_history.back().unwrap().was_me);
+        assert!(room.ollama_api_task.is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn idle_history_is_saved_and_loaded_back() {
+        let (mut state, dir) = test_state("unload-test", Arc::new(TestClient::new(""))).await;
+        state.config.ollama.unload_idle_rooms_after_secs = Some(0);
+
+        state.push_message(REPL_GID, Message::new("first", false, None, Vec::new()));
+        state.unload_idle_rooms().await;
+        assert!(state.rooms[&REPL_GID].message_history.is_empty());
+        let path = state.history_dir().join(history_file_name(REPL_GROUP));
+        assert!(path.exists());
+
+        handle_message(&mut state, REPL_GID, REPL_USER_UID, "second", Vec::new())
+            .await
+            .unwrap();
+        let history: Vec<_> = state.rooms[&REPL_GID]
+            .message_history
+            .iter()
+            .map(|msg| msg.message.as_str())
+            .collect();
+        assert_eq!(history, ["first", "second"]);
+        assert!(!path.exists());
+        assert_eq!(history_file_name("a b/é"), "a%20b%2f%c3%a9.json");
+
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
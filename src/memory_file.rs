@@ -0,0 +1,89 @@
+use crate::config::MemoryFormat;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+
+// group name -> memories
+pub type Memories = HashMap<String, Vec<String>>;
+
+pub fn parse(format: MemoryFormat, s: &str) -> anyhow::Result<Memories> {
+    Ok(match format {
+        MemoryFormat::Json => serde_json::from_str(s)?,
+        MemoryFormat::Yaml => serde_yaml::from_str(s)?,
+        MemoryFormat::Toml => toml::from_str(s)?,
+    })
+}
+
+pub fn serialize(format: MemoryFormat, memories: &Memories) -> anyhow::Result<String> {
+    Ok(match format {
+        MemoryFormat::Json => serde_json::to_string_pretty(memories)?,
+        MemoryFormat::Yaml => serde_yaml::to_string(memories)?,
+        MemoryFormat::Toml => toml::to_string_pretty(memories)?,
+    })
+}
+
+/// Replaces the file through a temporary one next to it, so that if the process dies midway,
+/// the file is either the old one or the new one, never something in between
+pub async fn write_atomically(path: &Path, contents: &str) -> io::Result<()> {
+    let tmp_path = tmp_path(path);
+
+    let mut file = File::create(&tmp_path).await?;
+    file.write_all(contents.as_bytes()).await?;
+    file.sync_all().await?;
+    drop(file);
+
+    fs::rename(&tmp_path, path).await
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+    name.push(".tmp");
+
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_round_trip() {
+        let memories = Memories::from([
+            (
+                "foo".to_string(),
+                vec!["user123 likes pizza".to_string(), "\"quoted\"".to_string()],
+            ),
+            ("bar baz".to_string(), Vec::new()),
+        ]);
+
+        for format in [MemoryFormat::Json, MemoryFormat::Yaml, MemoryFormat::Toml] {
+            let serialized = serialize(format, &memories).unwrap();
+            assert_eq!(parse(format, &serialized).unwrap(), memories, "{format:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_atomically() {
+        let dir = std::env::temp_dir().join(format!("memory-file-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("memories.json");
+        std::fs::write(&path, r#"{"foo": ["old"]}"#).unwrap();
+
+        // left behind by a write that was cut off
+        std::fs::write(tmp_path(&path), r#"{"foo": ["ne"#).unwrap();
+        let old = std::fs::read_to_string(&path).unwrap();
+        assert!(parse(MemoryFormat::Json, &old).is_ok());
+
+        write_atomically(&path, r#"{"foo": ["new"]}"#)
+            .await
+            .unwrap();
+        let new = parse(MemoryFormat::Json, &std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(new["foo"], ["new"]);
+        assert!(!tmp_path(&path).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
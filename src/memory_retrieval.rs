@@ -0,0 +1,133 @@
+use crate::ollama_client::OllamaClient;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+// inputs per embedding request
+const EMBEDDING_BATCH_SIZE: usize = 64;
+
+/// Memory text -> its embedding, shared with the generation tasks
+pub type EmbeddingCache = Arc<Mutex<HashMap<String, Vec<f32>>>>;
+
+/// What's needed to pick the relevant memories from within a spawned task
+pub struct Retrieval {
+    pub ollama: Arc<dyn OllamaClient>,
+    pub cache: EmbeddingCache,
+    pub model: String,
+    pub top_k: usize,
+}
+
+impl Retrieval {
+    /// The memories most similar to the text. None if embedding fails, then all of them are used
+    pub async fn select(&self, text: &str, memories: &[String]) -> Option<HashSet<String>> {
+        // the query and the memories that aren't cached yet go in batches, so that a cold cache
+        // doesn't take a request per memory
+        let uncached: Vec<String> = {
+            let cache = self.cache.lock().unwrap();
+            memories
+                .iter()
+                .filter(|memory| !cache.contains_key(*memory))
+                .cloned()
+                .collect::<HashSet<_>>()
+                .into_iter()
+                .collect()
+        };
+        let inputs: Vec<String> = std::iter::once(text.to_owned()).chain(uncached).collect();
+        let mut embedded = Vec::with_capacity(inputs.len());
+        for batch in inputs.chunks(EMBEDDING_BATCH_SIZE) {
+            match self.ollama.embed(self.model.clone(), batch.to_vec()).await {
+                Ok(embeddings) => embedded.extend(batch.iter().cloned().zip(embeddings)),
+                Err(e) => {
+                    warn!("Failed to embed memories, using all memories. {e:#}");
+                    return None;
+                }
+            }
+        }
+
+        let mut embedded = embedded.into_iter();
+        let (_text, query) = embedded.next()?;
+        let mut cache = self.cache.lock().unwrap();
+        cache.extend(embedded);
+
+        let mut scored: Vec<(f32, &String)> = memories
+            .iter()
+            .filter_map(|memory| {
+                let embedding = cache.get(memory)?;
+                Some((cosine_similarity(&query, embedding), memory))
+            })
+            .collect();
+        scored.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+
+        Some(
+            scored
+                .into_iter()
+                .take(self.top_k)
+                .map(|(_score, memory)| memory.clone())
+                .collect(),
+        )
+    }
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(a, b)| a * b).sum();
+    let norm_a = a.iter().map(|a| a * a).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|b| b * b).sum::<f32>().sqrt();
+
+    if norm_a == 0.0 || norm_b == 0.0 {
+        0.0
+    } else {
+        dot / (norm_a * norm_b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ollama_api::RequestBody;
+    use crate::ollama_client::{EmbeddingFuture, ModelsFuture, ResponseFuture};
+
+    /// Embeds a text as its length, counting the requests
+    #[derive(Default)]
+    struct LengthEmbedder {
+        requests: Mutex<usize>,
+    }
+
+    impl OllamaClient for LengthEmbedder {
+        fn generate(&self, _body: RequestBody) -> ResponseFuture {
+            unimplemented!()
+        }
+        fn warmup(&self, _model: String, _keep_alive: String) {}
+        fn embed(&self, _model: String, input: Vec<String>) -> EmbeddingFuture {
+            *self.requests.lock().unwrap() += 1;
+            let embeddings = input
+                .iter()
+                .map(|text| vec![text.len() as f32, 1.0])
+                .collect();
+
+            Box::pin(async move { Ok(embeddings) })
+        }
+        fn list_models(&self) -> ModelsFuture {
+            unimplemented!()
+        }
+    }
+
+    #[tokio::test]
+    async fn embeds_uncached_memories_in_batches() {
+        let client = Arc::new(LengthEmbedder::default());
+        let retrieval = Retrieval {
+            ollama: client.clone(),
+            cache: EmbeddingCache::default(),
+            model: "embed".to_owned(),
+            top_k: 1,
+        };
+        let memories: Vec<String> = (0..100).map(|i| "x".repeat(i + 1)).collect();
+
+        let selected = retrieval.select("xxxxx", &memories).await.unwrap();
+        assert_eq!(*client.requests.lock().unwrap(), 2);
+        assert_eq!(selected.len(), 1);
+
+        // only the query is embedded once the memories are cached
+        retrieval.select("xxxxx", &memories).await.unwrap();
+        assert_eq!(*client.requests.lock().unwrap(), 3);
+    }
+}
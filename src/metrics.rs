@@ -0,0 +1,169 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, error, info};
+
+/// Counters and gauges, rendered in the Prometheus text format
+#[derive(Default)]
+pub struct Metrics {
+    memories_added: AtomicU64,
+    memories_removed: AtomicU64,
+    // room name -> number of memories
+    room_memories: Mutex<HashMap<String, usize>>,
+    // snapshot served as json on /status
+    status: Mutex<Status>,
+}
+
+#[derive(Serialize, Default)]
+pub struct Status {
+    // the configured default, rooms can have their own
+    pub model: String,
+    pub rooms: Vec<RoomStatus>,
+}
+
+#[derive(Serialize)]
+pub struct RoomStatus {
+    pub name: String,
+    // the one it's actually generating with
+    pub model: String,
+    pub generating: bool,
+    // rfc 3339
+    pub last_response: Option<String>,
+    pub memories: usize,
+}
+
+impl Metrics {
+    pub fn memory_added(&self) {
+        self.memories_added.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn memory_removed(&self) {
+        self.memories_removed.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn set_room_memories(&self, room_name: &str, n: usize) {
+        self.room_memories
+            .lock()
+            .unwrap()
+            .insert(room_name.to_owned(), n);
+    }
+    pub fn set_status(&self, status: Status) {
+        *self.status.lock().unwrap() = status;
+    }
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        writeln!(out, "# TYPE multichat_ollama_memories_added_total counter").unwrap();
+        writeln!(
+            out,
+            "multichat_ollama_memories_added_total {}",
+            self.memories_added.load(Ordering::Relaxed)
+        )
+        .unwrap();
+
+        writeln!(
+            out,
+            "# TYPE multichat_ollama_memories_removed_total counter"
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "multichat_ollama_memories_removed_total {}",
+            self.memories_removed.load(Ordering::Relaxed)
+        )
+        .unwrap();
+
+        writeln!(out, "# TYPE multichat_ollama_memories gauge").unwrap();
+        for (room_name, n) in self.room_memories.lock().unwrap().iter() {
+            writeln!(
+                out,
+                "multichat_ollama_memories{{room=\"{}\"}} {n}",
+                escape_label(room_name)
+            )
+            .unwrap();
+        }
+
+        out
+    }
+}
+
+/// Binds the metrics endpoint and serves it in the background
+pub async fn serve(listen: SocketAddr, metrics: Arc<Metrics>) -> io::Result<()> {
+    let listener = TcpListener::bind(listen).await?;
+
+    info!("Serving metrics on {listen}");
+
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _addr)) => stream,
+                Err(e) => {
+                    error!("Failed to accept metrics connection. {e}");
+                    continue;
+                }
+            };
+
+            let metrics = metrics.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_connection(stream, &metrics).await {
+                    debug!("Metrics connection failed. {e}");
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+async fn handle_connection(mut stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    // we only need the request line, so a single read is enough
+    let mut buf = [0; 1024];
+    let n = stream.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..n]);
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+
+    let (status, content_type, body) = match path {
+        "/metrics" => ("200 OK", "text/plain; version=0.0.4", metrics.render()),
+        "/status" => (
+            "200 OK",
+            "application/json",
+            serde_json::to_string(&*metrics.status.lock().unwrap()).unwrap(),
+        ),
+        _ => ("404 Not Found", "text/plain", String::new()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+
+    Ok(())
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_memory_metrics() {
+        let metrics = Metrics::default();
+        metrics.memory_added();
+        metrics.memory_added();
+        metrics.memory_removed();
+        metrics.set_room_memories("fo\"o", 1);
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("multichat_ollama_memories_added_total 2\n"));
+        assert!(rendered.contains("multichat_ollama_memories_removed_total 1\n"));
+        assert!(rendered.contains("multichat_ollama_memories{room=\"fo\\\"o\"} 1\n"));
+    }
+}
@@ -1,8 +1,34 @@
+use crate::config::Ollama;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
 
 // REQUEST
 //////////
 
+/// Body of a request to either of the generation endpoints
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub enum RequestBody {
+    Chat(OllamaRequest),
+    Generate(GenerateRequest),
+}
+
+impl RequestBody {
+    pub fn path(&self) -> &'static str {
+        match self {
+            RequestBody::Chat(_) => "api/chat",
+            RequestBody::Generate(_) => "api/generate",
+        }
+    }
+    pub fn stream(&self) -> bool {
+        match self {
+            RequestBody::Chat(request) => request.stream,
+            RequestBody::Generate(request) => request.stream,
+        }
+    }
+}
+
 #[derive(Serialize, Debug)]
 pub struct OllamaRequest {
     pub model: String,
@@ -12,7 +38,7 @@ pub struct OllamaRequest {
     pub options: OllamaRequestOptions,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, Clone)]
 pub struct OllamaRequestMessage {
     pub role: String,
     pub content: String,
@@ -20,10 +46,73 @@ pub struct OllamaRequestMessage {
     pub images: Vec<String>,
 }
 
+#[derive(Serialize, Debug)]
+pub struct GenerateRequest {
+    pub model: String,
+    pub prompt: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<String>,
+    // the prompt is already templated, so ollama must not apply the model's template
+    pub raw: bool,
+    pub stream: bool,
+    pub keep_alive: String,
+    pub options: OllamaRequestOptions,
+}
+
+/// For /api/embed, which embeds a batch of inputs at once
+#[derive(Serialize, Debug)]
+pub struct EmbedRequest {
+    pub model: String,
+    pub input: Vec<String>,
+}
+
+/// A generate request without a prompt, which only loads the model
+#[derive(Serialize, Debug)]
+pub struct WarmupRequest {
+    pub model: String,
+    pub keep_alive: String,
+}
+
 #[derive(Serialize, Debug)]
 pub struct OllamaRequestOptions {
     pub temperature: f32,
     pub top_k: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repeat_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub num_ctx: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    // any other options, passed through as they are
+    #[serde(flatten)]
+    pub extra: HashMap<String, Value>,
+}
+
+impl OllamaRequestOptions {
+    pub fn new(config: &Ollama) -> Self {
+        let mut options = OllamaRequestOptions {
+            temperature: config.temperature,
+            top_k: config.top_k,
+            top_p: config.top_p,
+            repeat_penalty: config.repeat_penalty,
+            num_ctx: config.num_ctx,
+            seed: config.seed,
+            extra: HashMap::new(),
+        };
+
+        // the typed options take precedence
+        let typed = serde_json::to_value(&options).expect("options serialize to an object");
+        options.extra = config
+            .extra_options
+            .iter()
+            .filter(|(key, _)| typed.get(key.as_str()).is_none())
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+
+        options
+    }
 }
 
 // RESPONSE
@@ -32,9 +121,58 @@ pub struct OllamaRequestOptions {
 #[derive(Deserialize, Debug)]
 pub struct OllamaResponse {
     pub message: OllamaResponseMessage,
+    #[serde(flatten)]
+    pub usage: Usage,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct OllamaResponseMessage {
     pub content: String,
 }
+
+#[derive(Deserialize, Debug)]
+pub struct GenerateResponse {
+    pub response: String,
+    #[serde(flatten)]
+    pub usage: Usage,
+}
+
+/// Statistics of a finished generation, older ollama versions may not send them
+#[derive(Deserialize, Debug, Default)]
+pub struct Usage {
+    pub prompt_eval_count: Option<u64>,
+    pub eval_count: Option<u64>,
+    // nanoseconds
+    pub eval_duration: Option<u64>,
+    pub total_duration: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct EmbedResponse {
+    // in the order of the inputs
+    pub embeddings: Vec<Vec<f32>>,
+}
+
+/// The models available on the server, from /api/tags
+#[derive(Deserialize, Debug)]
+pub struct ListModelsResponse {
+    pub models: Vec<ListedModel>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ListedModel {
+    pub name: String,
+}
+
+/// One line of a streamed response, from either endpoint
+#[derive(Deserialize, Debug)]
+pub struct StreamChunk {
+    pub message: Option<OllamaResponseMessage>,
+    pub response: Option<String>,
+    #[serde(default)]
+    pub done: bool,
+    pub error: Option<String>,
+    // only in the last chunk
+    #[serde(flatten)]
+    pub usage: Usage,
+}
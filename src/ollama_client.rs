@@ -0,0 +1,413 @@
+use crate::config::Ollama;
+use crate::ollama_api::{
+    EmbedRequest, EmbedResponse, GenerateResponse, ListModelsResponse, OllamaResponse, RequestBody,
+    StreamChunk, Usage, WarmupRequest,
+};
+use crate::rate_limit::RateLimiter;
+use anyhow::{bail, Context};
+use reqwest::StatusCode;
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{sleep, timeout};
+use tracing::{info, warn};
+use url::Url;
+
+pub type ResponseFuture = Pin<Box<dyn Future<Output = anyhow::Result<String>> + Send>>;
+pub type EmbeddingFuture = Pin<Box<dyn Future<Output = anyhow::Result<Vec<Vec<f32>>>> + Send>>;
+pub type ModelsFuture = Pin<Box<dyn Future<Output = anyhow::Result<Vec<String>>> + Send>>;
+
+// embeddings are waited for before generating, so they get less time than generations
+const EMBEDDING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Talks to the ollama api. A trait so that the generation pipeline can be tested without a live ollama
+pub trait OllamaClient: Send + Sync {
+    /// Sends a generation request, the returned future resolves to the generated text
+    fn generate(&self, body: RequestBody) -> ResponseFuture;
+    /// Loads a model in the background
+    fn warmup(&self, model: String, keep_alive: String);
+    /// Embeds the texts with an embedding model, in a single request
+    fn embed(&self, model: String, input: Vec<String>) -> EmbeddingFuture;
+    /// Names of the models available on the server
+    fn list_models(&self) -> ModelsFuture;
+}
+
+/// The real client, over http
+pub struct HttpClient {
+    reqw: reqwest::Client,
+    base_url: Url,
+    auth_user: String,
+    auth_password: String,
+    connect_retries: u32,
+    connect_retry_backoff: Duration,
+    max_retries: u32,
+    retry_backoff: Duration,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    timeout: Duration,
+}
+
+impl HttpClient {
+    pub fn new(config: &Ollama) -> Self {
+        HttpClient {
+            reqw: reqwest::Client::new(),
+            base_url: config.base_url.clone(),
+            auth_user: config.basic_auth_user.clone(),
+            auth_password: config.basic_auth_password.clone(),
+            connect_retries: config.connect_retries,
+            connect_retry_backoff: Duration::from_millis(config.connect_retry_backoff_ms),
+            max_retries: config.ollama_max_retries,
+            retry_backoff: Duration::from_millis(config.ollama_retry_backoff_ms),
+            rate_limiter: config
+                .requests_per_minute
+                .map(|per_minute| Arc::new(RateLimiter::new(per_minute))),
+            timeout: Duration::from_secs(config.ollama_timeout_secs),
+        }
+    }
+}
+
+impl OllamaClient for HttpClient {
+    fn generate(&self, body: RequestBody) -> ResponseFuture {
+        let mut url = self.base_url.clone();
+        url.set_path(body.path());
+
+        let reqw = self.reqw.clone();
+        let auth_user = self.auth_user.clone();
+        let auth_password = self.auth_password.clone();
+
+        let connect_retries = self.connect_retries;
+        let connect_retry_backoff = self.connect_retry_backoff;
+        let max_retries = self.max_retries;
+        let retry_backoff = self.retry_backoff;
+        let rate_limiter = self.rate_limiter.clone();
+        let request_timeout = self.timeout;
+
+        let request = async move {
+            // connection failures and server errors are retried separately
+            let mut connect_attempt = 0;
+            let mut attempt = 0;
+            let response = loop {
+                let response = reqw
+                    .post(url.clone())
+                    .basic_auth(&auth_user, Some(&auth_password))
+                    .json(&body)
+                    .send()
+                    .await;
+
+                match response {
+                    // DNS and connection failures are often transient, e.g. while service discovery catches up
+                    Err(e) if e.is_connect() && connect_attempt < connect_retries => {
+                        let backoff = backoff(connect_retry_backoff, connect_attempt);
+                        connect_attempt += 1;
+                        warn!("Failed to connect to ollama (attempt {connect_attempt}), retrying in {backoff:?}. {e}");
+                        sleep(backoff).await;
+                    }
+                    // so is a dropped connection
+                    Err(e) if !e.is_connect() && e.is_request() && attempt < max_retries => {
+                        let backoff = backoff(retry_backoff, attempt);
+                        attempt += 1;
+                        warn!("Request to ollama failed (attempt {attempt}), retrying in {backoff:?}. {e}");
+                        sleep(backoff).await;
+                    }
+                    // and server errors, e.g. while the model is being loaded. client errors aren't
+                    Ok(response)
+                        if response.status().is_server_error() && attempt < max_retries =>
+                    {
+                        let backoff = backoff(retry_backoff, attempt);
+                        attempt += 1;
+                        warn!(
+                            "Ollama responded with {} (attempt {attempt}), retrying in {backoff:?}",
+                            response.status()
+                        );
+                        sleep(backoff).await;
+                    }
+                    response => break response?,
+                }
+            };
+
+            // what a missing model, or something else than ollama at base-url, looks like
+            if response.status() == StatusCode::NOT_FOUND {
+                let message = response.text().await.unwrap_or_default();
+                bail!("not found, check that model is pulled and base-url points at ollama. {message}");
+            }
+            let mut response = response.error_for_status()?;
+
+            let (content, usage) = if body.stream() {
+                let mut parser = StreamParser::default();
+                while let Some(bytes) = response.chunk().await? {
+                    parser.feed(&bytes)?;
+                }
+                parser.finish()?
+            } else {
+                match body {
+                    RequestBody::Chat(_) => {
+                        let response = response.json::<OllamaResponse>().await?;
+                        (response.message.content, response.usage)
+                    }
+                    RequestBody::Generate(_) => {
+                        let response = response.json::<GenerateResponse>().await?;
+                        (response.response, response.usage)
+                    }
+                }
+            };
+            log_usage(&usage);
+
+            Ok::<_, anyhow::Error>(content)
+        };
+
+        Box::pin(async move {
+            // waiting for the rate limit doesn't count towards the timeout
+            if let Some(rate_limiter) = rate_limiter {
+                rate_limiter.acquire().await;
+            }
+
+            match timeout(request_timeout, request).await {
+                Ok(result) => result,
+                Err(_) => bail!("ollama didn't respond within {request_timeout:?}"),
+            }
+        })
+    }
+    fn warmup(&self, model: String, keep_alive: String) {
+        let mut url = self.base_url.clone();
+        url.set_path("api/generate");
+
+        let request = self
+            .reqw
+            .post(url)
+            .basic_auth(&self.auth_user, Some(&self.auth_password))
+            .json(&WarmupRequest {
+                model: model.clone(),
+                keep_alive,
+            });
+        let rate_limiter = self.rate_limiter.clone();
+
+        tokio::spawn(async move {
+            if let Some(rate_limiter) = rate_limiter {
+                rate_limiter.acquire().await;
+            }
+
+            match request.send().await.and_then(|r| r.error_for_status()) {
+                Ok(_) => info!("Loaded model {model}"),
+                Err(e) => warn!("Failed to load model {model}. {e}"),
+            }
+        });
+    }
+    fn embed(&self, model: String, input: Vec<String>) -> EmbeddingFuture {
+        let mut url = self.base_url.clone();
+        url.set_path("api/embed");
+
+        let inputs = input.len();
+        let request = self
+            .reqw
+            .post(url)
+            .basic_auth(&self.auth_user, Some(&self.auth_password))
+            .timeout(EMBEDDING_TIMEOUT)
+            .json(&EmbedRequest { model, input });
+        let rate_limiter = self.rate_limiter.clone();
+
+        Box::pin(async move {
+            // the timeout only starts with the request
+            if let Some(rate_limiter) = rate_limiter {
+                rate_limiter.acquire().await;
+            }
+
+            let response = request.send().await?.error_for_status()?;
+            let embeddings = response.json::<EmbedResponse>().await?.embeddings;
+            if embeddings.len() != inputs {
+                bail!("got {} embeddings for {inputs} inputs", embeddings.len());
+            }
+
+            Ok(embeddings)
+        })
+    }
+    fn list_models(&self) -> ModelsFuture {
+        let mut url = self.base_url.clone();
+        url.set_path("api/tags");
+
+        let request = self
+            .reqw
+            .get(url)
+            .basic_auth(&self.auth_user, Some(&self.auth_password))
+            .timeout(self.timeout);
+
+        Box::pin(async move {
+            let response = request.send().await?.error_for_status()?;
+            let models = response.json::<ListModelsResponse>().await?.models;
+
+            Ok(models.into_iter().map(|model| model.name).collect())
+        })
+    }
+}
+
+/// Exponential backoff, saturating instead of overflowing for large retry counts
+fn backoff(base: Duration, attempt: u32) -> Duration {
+    base.saturating_mul(1u32.checked_shl(attempt).unwrap_or(u32::MAX))
+}
+
+fn log_usage(usage: &Usage) {
+    let (Some(tokens), Some(duration)) = (usage.eval_count, usage.total_duration) else {
+        return;
+    };
+    let duration = Duration::from_nanos(duration);
+    let tokens_per_sec = match usage.eval_duration {
+        Some(eval_duration) if eval_duration > 0 => tokens as f64 / (eval_duration as f64 / 1e9),
+        _ => tokens as f64 / duration.as_secs_f64(),
+    };
+
+    info!(
+        "Generated {tokens} tokens in {:.1}s, {tokens_per_sec:.0} tok/s, the prompt was {} tokens",
+        duration.as_secs_f64(),
+        usage
+            .prompt_eval_count
+            .map_or("an unknown number of".to_owned(), |count| count.to_string()),
+    );
+}
+
+/// Collects the content of a streamed (NDJSON) response, which arrives in arbitrarily split chunks
+#[derive(Default)]
+struct StreamParser {
+    // an incomplete line, which may end in the middle of a utf-8 char
+    partial: Vec<u8>,
+    content: String,
+    usage: Usage,
+    done: bool,
+}
+
+impl StreamParser {
+    fn feed(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        self.partial.extend_from_slice(bytes);
+        while let Some(end) = self.partial.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.partial.drain(..=end).collect();
+            self.parse_line(&line)?;
+        }
+
+        Ok(())
+    }
+    fn finish(mut self) -> anyhow::Result<(String, Usage)> {
+        // the last line doesn't have to end with a newline
+        let rest = mem::take(&mut self.partial);
+        self.parse_line(&rest)?;
+
+        if !self.done {
+            bail!("ollama stopped streaming before the response was done");
+        }
+
+        Ok((self.content, self.usage))
+    }
+    fn parse_line(&mut self, line: &[u8]) -> anyhow::Result<()> {
+        if self.done || line.trim_ascii().is_empty() {
+            return Ok(());
+        }
+
+        let chunk: StreamChunk =
+            serde_json::from_slice(line).context("parsing streamed response")?;
+        if let Some(error) = chunk.error {
+            bail!("{error}");
+        }
+        // keep-alive and metadata chunks, like the final one, have no content
+        if let Some(message) = chunk.message {
+            self.content.push_str(&message.content);
+        }
+        if let Some(response) = chunk.response {
+            self.content.push_str(&response);
+        }
+        self.usage = chunk.usage;
+        self.done = chunk.done;
+
+        Ok(())
+    }
+}
+
+/// Answers every generation with the same reply and keeps the requests, for tests
+#[cfg(test)]
+pub struct TestClient {
+    pub reply: String,
+    pub requests: std::sync::Mutex<Vec<RequestBody>>,
+}
+
+#[cfg(test)]
+impl TestClient {
+    pub fn new(reply: &str) -> Self {
+        TestClient {
+            reply: reply.to_owned(),
+            requests: Default::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl OllamaClient for TestClient {
+    fn generate(&self, body: RequestBody) -> ResponseFuture {
+        self.requests.lock().unwrap().push(body);
+        let reply = self.reply.clone();
+
+        Box::pin(async move { Ok(reply) })
+    }
+    fn warmup(&self, _model: String, _keep_alive: String) {}
+    fn list_models(&self) -> ModelsFuture {
+        Box::pin(async { Ok(Vec::new()) })
+    }
+    fn embed(&self, _model: String, _input: Vec<String>) -> EmbeddingFuture {
+        Box::pin(async { bail!("no embedding model in tests") })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RECORDED_STREAM: &str = concat!(
+        r#"{"model":"llama3","message":{"role":"assistant","content":"Héllo"},"done":false}"#,
+        "\n",
+        r#"{"model":"llama3","message":{"role":"assistant","content":""},"done":false}"#,
+        "\n\n",
+        r#"{"model":"llama3","message":{"role":"assistant","content":" wörld 🎉"},"done":false}"#,
+        "\n",
+        r#"{"model":"llama3","message":{"role":"assistant","content":""},"done":true,"eval_count":3}"#,
+        "\n",
+    );
+
+    #[test]
+    fn backoff_saturates() {
+        let base = Duration::from_millis(500);
+        assert_eq!(backoff(base, 0), base);
+        assert_eq!(backoff(base, 3), Duration::from_secs(4));
+        assert_eq!(backoff(base, 100), base * u32::MAX);
+    }
+
+    #[test]
+    fn parses_stream_split_anywhere() {
+        let bytes = RECORDED_STREAM.as_bytes();
+        // every split point, including ones in the middle of multi-byte chars
+        for split in 0..bytes.len() {
+            let mut parser = StreamParser::default();
+            parser.feed(&bytes[..split]).unwrap();
+            parser.feed(&bytes[split..]).unwrap();
+            let (content, usage) = parser.finish().unwrap();
+            assert_eq!(content, "Héllo wörld 🎉");
+            assert_eq!(usage.eval_count, Some(3));
+        }
+    }
+
+    #[test]
+    fn parses_generate_stream_without_trailing_newline() {
+        let mut parser = StreamParser::default();
+        parser
+            .feed(b"{\"response\":\"hi\",\"done\":false}\n{\"response\":\"\",\"done\":true}")
+            .unwrap();
+        assert_eq!(parser.finish().unwrap().0, "hi");
+    }
+
+    #[test]
+    fn unfinished_or_failed_stream_is_an_error() {
+        let mut parser = StreamParser::default();
+        parser
+            .feed(RECORDED_STREAM.lines().next().unwrap().as_bytes())
+            .unwrap();
+        assert!(parser.finish().is_err());
+
+        let mut parser = StreamParser::default();
+        assert!(parser.feed(b"{\"error\":\"model crashed\"}\n").is_err());
+    }
+}
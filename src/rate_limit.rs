@@ -0,0 +1,65 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+/// Token bucket bounding the rate of requests, allowing bursts of up to a minute's worth
+pub struct RateLimiter {
+    per_minute: u32,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(per_minute: u32) -> Self {
+        let per_minute = per_minute.max(1);
+        RateLimiter {
+            per_minute,
+            bucket: Mutex::new(Bucket {
+                tokens: per_minute as f64,
+                updated: Instant::now(),
+            }),
+        }
+    }
+    /// Waits until a request may be made
+    pub async fn acquire(&self) {
+        let per_sec = self.per_minute as f64 / 60.0;
+
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().unwrap();
+
+                let now = Instant::now();
+                let refill = now.duration_since(bucket.updated).as_secs_f64() * per_sec;
+                bucket.tokens = (bucket.tokens + refill).min(self.per_minute as f64);
+                bucket.updated = now;
+
+                if bucket.tokens >= 1.0 {
+                    bucket.tokens -= 1.0;
+                    return;
+                }
+
+                Duration::from_secs_f64((1.0 - bucket.tokens) / per_sec)
+            };
+
+            sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn allows_a_burst() {
+        let limiter = RateLimiter::new(2);
+        limiter.acquire().await;
+        limiter.acquire().await;
+
+        assert!(limiter.bucket.lock().unwrap().tokens < 1.0);
+    }
+}
@@ -1,58 +1,263 @@
+use crate::config::{OldTimestamps, Ollama, TimestampFormat};
 use base64::Engine;
 use chrono::{DateTime, Utc};
 use chrono_humanize::{Accuracy, HumanTime, Tense};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, VecDeque};
-use tokio::task::JoinHandle;
+use std::time::Instant;
+use tokio::task::AbortHandle;
+
+pub type OllamaTask = AbortHandle;
 
 /// State of a particular room/group that ollama is in
 pub struct RoomState {
     pub my_uid: u32,
     pub room_name: String,
+    // the name the bot responds to in this room
+    pub mention_name: String,
+    // never respond in this room, only keep track of it
+    pub observe_only: bool,
+    // whether <MEMORY> tags in replies are saved
+    pub allow_memory_creation: bool,
+    pub settings: ModelSettings,
     pub usernames: HashMap<u32, String>,
+    // uid -> text of their last message, for ignoring repeats
+    pub last_message_by_user: HashMap<u32, String>,
     pub message_history: VecDeque<Message>,
     pub memories: Vec<String>,
 
-    pub ollama_api_task: Option<JoinHandle<anyhow::Result<String>>>,
+    pub ollama_api_task: Option<OllamaTask>,
+    // when a debounced generation is due to start
+    pub pending_generation: Option<Instant>,
+    // when the current generation was started and how many messages had been received by then
+    pub generation_started: Option<(Instant, u64)>,
+    // when the last generation was started, it isn't reset when it finishes
+    pub last_generation_start: Option<Instant>,
+    // number of messages received from other users
+    pub received_messages: u64,
+    // number of messages received since the bot last said something
+    pub messages_since_reply: u64,
+    // set with /setprompt, used instead of the configured system prompt
+    pub system_prompt_override: Option<String>,
+    // memory consolidation in progress, and its result waiting for confirmation
+    pub consolidation_task: Option<OllamaTask>,
+    pub pending_consolidation: Option<Vec<String>>,
+    // same for memories relearned from the history, which are added to the current ones
+    pub relearn_task: Option<OllamaTask>,
+    pub pending_relearn: Option<Vec<String>>,
+    // a /model switch waiting for ollama to list its models
+    pub model_switch_task: Option<OllamaTask>,
+    // the last failed generation, for diagnostics
+    pub last_error: Option<(DateTime<Utc>, String)>,
+    // failed generations in a row, and until when mentions are ignored because of them
+    pub consecutive_failures: u32,
+    pub error_cooldown: Option<Instant>,
+    // whether someone was already told about the cooldown
+    pub cooldown_notice_sent: bool,
+    // when the bot last replied
+    pub last_response: Option<DateTime<Utc>>,
+    // recent events, oldest first, for /activity
+    pub activity: VecDeque<(DateTime<Utc>, String)>,
+    // when the last message was sent or received here
+    pub last_active: Instant,
+    // the history was saved to disk for being idle, it's loaded back on the next message
+    pub history_unloaded: bool,
+}
+
+/// How replies are generated in a room, the configuration with the group's overrides applied
+pub struct ModelSettings {
+    pub model: String,
+    // how long ollama keeps the model loaded after a request from this room
+    pub keep_alive: String,
+    pub system_prompt: Vec<String>,
+    pub temperature: f32,
+    pub top_k: u32,
 }
 
 #[derive(Debug)]
 pub struct Message {
     pub was_me: bool,
+    // sender's username, if it was known when the message was received
+    pub username: Option<String>,
     pub time: DateTime<Utc>,
     pub message: String,
     // base64
-    pub image: Option<String>,
+    pub images: Vec<String>,
 }
 
 impl RoomState {
-    pub fn new(my_uid: u32, room_name: String, memories: Vec<String>) -> Self {
+    pub fn new(
+        my_uid: u32,
+        room_name: String,
+        mention_name: String,
+        observe_only: bool,
+        allow_memory_creation: bool,
+        settings: ModelSettings,
+        memories: Vec<String>,
+    ) -> Self {
         RoomState {
             my_uid,
             room_name,
+            mention_name,
+            observe_only,
+            allow_memory_creation,
+            settings,
             usernames: HashMap::new(),
+            last_message_by_user: HashMap::new(),
             message_history: VecDeque::new(),
             memories,
             ollama_api_task: None,
+            pending_generation: None,
+            generation_started: None,
+            last_generation_start: None,
+            received_messages: 0,
+            messages_since_reply: 0,
+            system_prompt_override: None,
+            consolidation_task: None,
+            pending_consolidation: None,
+            relearn_task: None,
+            pending_relearn: None,
+            model_switch_task: None,
+            last_error: None,
+            consecutive_failures: 0,
+            error_cooldown: None,
+            cooldown_notice_sent: false,
+            last_response: None,
+            activity: VecDeque::new(),
+            last_active: Instant::now(),
+            history_unloaded: false,
+        }
+    }
+    /// Drops the reply that's scheduled or being generated, stopping its request
+    pub fn cancel_generation(&mut self) {
+        self.pending_generation = None;
+        self.generation_started = None;
+        if let Some(task) = self.ollama_api_task.take() {
+            task.abort();
+        }
+    }
+    /// Whether nobody but the bot is left in the room
+    pub fn is_alone(&self) -> bool {
+        self.usernames.keys().all(|&uid| uid == self.my_uid)
+    }
+}
+
+/// A message as it's saved in the history file of an idle room
+#[derive(Serialize, Deserialize)]
+pub struct SavedMessage {
+    was_me: bool,
+    username: Option<String>,
+    // unix milliseconds
+    time: i64,
+    message: String,
+    images: Vec<String>,
+}
+
+impl From<&Message> for SavedMessage {
+    fn from(msg: &Message) -> Self {
+        SavedMessage {
+            was_me: msg.was_me,
+            username: msg.username.clone(),
+            time: msg.time.timestamp_millis(),
+            message: msg.message.clone(),
+            images: msg.images.clone(),
+        }
+    }
+}
+
+impl From<SavedMessage> for Message {
+    fn from(msg: SavedMessage) -> Self {
+        Message {
+            was_me: msg.was_me,
+            username: msg.username,
+            time: DateTime::from_timestamp_millis(msg.time).unwrap_or_default(),
+            message: msg.message,
+            images: msg.images,
         }
     }
 }
 
 impl Message {
-    pub fn new(msg: &str, was_me: bool, image: Option<Vec<u8>>) -> Self {
+    pub fn new(msg: &str, was_me: bool, username: Option<String>, images: Vec<Vec<u8>>) -> Self {
         Message {
             was_me,
+            username,
             time: Utc::now(),
             message: msg.to_string(),
-            image: image.map(|bytes| base64::engine::general_purpose::STANDARD.encode(bytes)),
+            images: images
+                .into_iter()
+                .map(|bytes| base64::engine::general_purpose::STANDARD.encode(bytes))
+                .collect(),
         }
     }
-    pub fn format(&self) -> String {
-        let timestamp = HumanTime::from(self.time).to_text_en(Accuracy::Rough, Tense::Past);
+    pub fn format(&self, config: &Ollama) -> String {
+        let timestamp = match &config.timestamp_format {
+            TimestampFormat::Humanized => self.humanized_timestamp(config),
+            TimestampFormat::Absolute(pattern) => Some(
+                self.time
+                    .with_timezone(&config.timezone)
+                    .format(pattern)
+                    .to_string(),
+            ),
+            TimestampFormat::None => None,
+        };
 
         if self.was_me {
-            format!("{}", self.message)
+            return self.message.clone();
+        }
+
+        // the user may have spoken before their join was processed
+        let username = self.username.as_deref().unwrap_or("someone");
+        match timestamp {
+            Some(timestamp) => format!("{timestamp} {username}: {}", self.message),
+            None => format!("{username}: {}", self.message),
+        }
+    }
+    /// Human-readable line for transcripts, unlike `format` which is shaped for the prompt
+    pub fn format_transcript(&self, my_name: &str, timezone: Tz) -> String {
+        let name = if self.was_me {
+            my_name
         } else {
-            format!("{} {}", timestamp, self.message)
+            self.username.as_deref().unwrap_or("unknown user")
+        };
+        let images = " [image]".repeat(self.images.len());
+
+        format!(
+            "[{}] {}: {}{}",
+            self.time
+                .with_timezone(&timezone)
+                .format("%Y-%m-%d %H:%M %Z"),
+            name,
+            self.message,
+            images
+        )
+    }
+    /// Forgets the image data, leaving markers in the text
+    pub fn drop_images(&mut self) {
+        for _ in self.images.drain(..) {
+            self.message.push_str(" [image]");
+        }
+    }
+    /// Relative time, or what old messages get instead
+    fn humanized_timestamp(&self, config: &Ollama) -> Option<String> {
+        let age = Utc::now().signed_duration_since(self.time).num_seconds();
+        let too_old = config
+            .relative_time_max_secs
+            .is_some_and(|max| age > max as i64);
+
+        match (too_old, config.old_timestamps) {
+            (false, _) => Some(self.humanized_time()),
+            (true, OldTimestamps::Date) => Some(
+                self.time
+                    .with_timezone(&config.timezone)
+                    .format("%Y-%m-%d")
+                    .to_string(),
+            ),
+            (true, OldTimestamps::Hide) => None,
         }
     }
+    fn humanized_time(&self) -> String {
+        HumanTime::from(self.time).to_text_en(Accuracy::Rough, Tense::Past)
+    }
 }
@@ -0,0 +1,53 @@
+use crate::error::Error;
+use crate::McClient;
+use anyhow::anyhow;
+use std::future::Future;
+use std::pin::Pin;
+
+pub type SendFuture<'a> = Pin<Box<dyn Future<Output = Result<(), Error>> + Send + 'a>>;
+
+/// Where the messages of the bot go
+pub trait Sink: Send {
+    /// Sends a message to the group, as the user with the uid
+    fn send_message<'a>(&'a mut self, gid: u32, uid: u32, message: &'a str) -> SendFuture<'a>;
+    /// The multichat connection, if there is one right now
+    fn client(&mut self) -> Option<&mut McClient>;
+}
+
+/// Sends to multichat. Without a connection, like while reconnecting, sending fails
+pub struct Multichat {
+    pub client: Option<McClient>,
+}
+
+impl Sink for Multichat {
+    fn send_message<'a>(&'a mut self, gid: u32, uid: u32, message: &'a str) -> SendFuture<'a> {
+        Box::pin(async move {
+            let client = self
+                .client
+                .as_mut()
+                .ok_or_else(|| Error::multichat(anyhow!("not connected")))?;
+
+            client
+                .send_message(gid, uid, message, &[])
+                .await
+                .map_err(Error::multichat)
+        })
+    }
+    fn client(&mut self) -> Option<&mut McClient> {
+        self.client.as_mut()
+    }
+}
+
+/// Prints the messages, for the repl
+pub struct Stdout;
+
+impl Sink for Stdout {
+    fn send_message<'a>(&'a mut self, _gid: u32, _uid: u32, message: &'a str) -> SendFuture<'a> {
+        println!("{message}");
+
+        Box::pin(async { Ok(()) })
+    }
+    fn client(&mut self) -> Option<&mut McClient> {
+        None
+    }
+}